@@ -0,0 +1,39 @@
+// Compare evaluating the same expressions many times with the closure
+// compiler against walking the tokens each time. Run with `cargo bench`.
+use std::time::{Duration, Instant};
+
+use day18::closure::compile;
+use day18::env::Env;
+use day18::eval_expr;
+use day18::parser::parse;
+use day18::prec::PrecTable;
+
+const ROUNDS: usize = 2_000;
+
+fn time<F: FnMut() -> i64>(mut f: F) -> (Duration, i64) {
+    let start = Instant::now();
+    let mut total = 0i64;
+    for _ in 0..ROUNDS {
+        total = total.wrapping_add(f());
+    }
+    (start.elapsed(), total)
+}
+
+fn main() {
+    let input = include_str!("../input.txt");
+    let lines: Vec<&str> = input.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).collect();
+    let env: Env = Env::new();
+
+    for (name, table) in [("Part 1", PrecTable::left_to_right()), ("Part 2", PrecTable::addition_first())].iter() {
+        let tokens: Vec<_> = lines.iter().map(|l| parse(l).unwrap()).collect();
+        let compiled: Vec<_> = lines.iter().map(|l| compile::<i64>(l, table).unwrap()).collect();
+
+        let (walked, a) = time(|| tokens.iter().map(|t| eval_expr(t, table, &env).unwrap()).sum());
+        let (closures, b) = time(|| compiled.iter().map(|c| c.eval(&env).unwrap()).sum());
+        assert_eq!(a, b, "Evaluators disagree");
+
+        let per = |d: Duration| d / (ROUNDS * lines.len()) as u32;
+        println!("{}: eval_expr {:?}/expr, closures {:?}/expr ({:.1}x)",
+            name, per(walked), per(closures), walked.as_secs_f64() / closures.as_secs_f64());
+    }
+}
@@ -0,0 +1,373 @@
+use crate::env::Env;
+use crate::func::builtin_arity;
+use crate::error::{Error, EvalError};
+use crate::lexer::Span;
+use crate::num::{truthy, Decimal, Number};
+use crate::parser::{fold_expr, DepthLimit, Fold, Token};
+use crate::prec::{Assoc, Op, PrecTable};
+use crate::rpn::fold_rpn;
+
+// Expression tree with the grouping made explicit
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Expr {
+    Num(i64),
+    Decimal(Decimal),
+    Var(String),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>)
+}
+
+struct AstBuilder;
+
+impl Fold for AstBuilder {
+    type Out = Expr;
+
+    fn num(&self, n: i64, _: Span) -> Result<Expr, Error> {
+        Ok(Expr::Num(n))
+    }
+
+    fn decimal(&self, d: Decimal, _: Span) -> Result<Expr, Error> {
+        Ok(Expr::Decimal(d))
+    }
+
+    fn var(&self, name: &str, _: Span) -> Result<Expr, Error> {
+        Ok(Expr::Var(name.to_string()))
+    }
+
+    fn neg(&self, x: Expr, _: Span) -> Result<Expr, Error> {
+        Ok(Expr::Neg(Box::new(x)))
+    }
+
+    fn not(&self, x: Expr, _: Span) -> Result<Expr, Error> {
+        Ok(Expr::Not(Box::new(x)))
+    }
+
+    fn bin(&self, op: Op, a: Expr, b: Expr, _: Span) -> Result<Expr, Error> {
+        Ok(Expr::Bin(op, Box::new(a), Box::new(b)))
+    }
+
+    fn cond(&self, c: Expr, a: Expr, b: Expr, _: Span) -> Result<Expr, Error> {
+        Ok(Expr::Cond(Box::new(c), Box::new(a), Box::new(b)))
+    }
+
+    fn call(&self, name: &str, args: Vec<Expr>, _: Span) -> Result<Expr, Error> {
+        Ok(Expr::Call(name.to_string(), args))
+    }
+}
+
+impl Expr {
+    // Group the bracket tree according to the table. Everything done with
+    // an Expr recurses, so trees deeper than MAX_DEPTH are refused.
+    pub fn build(tokens: &[Token], table: &PrecTable) -> Result<Expr, Error> {
+        fold_expr(tokens, table, &DepthLimit(AstBuilder)).map(|(e, _)| e)
+    }
+
+    pub fn from_rpn(src: &str) -> Result<Expr, Error> {
+        fold_rpn(src, builtin_arity, &DepthLimit(AstBuilder)).map(|(e, _)| e)
+    }
+
+    pub fn eval<N: Number>(&self, env: &Env<N>) -> Result<N, EvalError> {
+        match self {
+            Expr::Num(n) => Ok(N::from_i64(*n)),
+            Expr::Decimal(d) => N::from_decimal(*d),
+            Expr::Var(name) => env.get(name).ok_or_else(|| EvalError::UndefinedVar(name.clone())),
+            Expr::Neg(x) => x.eval(env)?.negate(),
+            Expr::Not(x) => Ok(N::from_i64(!truthy(&x.eval(env)?) as i64)),
+            // The right side of && and || is only evaluated when it matters
+            Expr::Bin(op, a, b) if op.is_logic() => {
+                let a = truthy(&a.eval(env)?);
+                let b = if a == (*op == Op::And) { truthy(&b.eval(env)?) } else { a };
+                Ok(N::from_i64(b as i64))
+            }
+            Expr::Bin(op, a, b) => op.apply(a.eval(env)?, b.eval(env)?),
+            Expr::Cond(c, a, b) => {
+                if truthy(&c.eval(env)?) { a.eval(env) } else { b.eval(env) }
+            }
+            Expr::Call(name, args) => {
+                let args = args.iter().map(|a| a.eval(env)).collect::<Result<Vec<N>, _>>()?;
+                env.call(name, &args)
+            }
+        }
+    }
+
+    // Print with only the parentheses needed to get the same grouping back
+    // when parsed under `table`
+    pub fn pretty(&self, table: &PrecTable) -> String {
+        let mut out = String::new();
+        self.write_min(table, None, &mut out);
+        out
+    }
+
+    // Print with every operation parenthesised, readable under any table
+    pub fn pretty_full(&self) -> String {
+        let mut out = String::new();
+        self.write_full(true, &mut out);
+        out
+    }
+
+    // `follow` is the precedence of the operator printed straight after this
+    // expression, if any. A unary minus would swallow it if it binds as
+    // tightly as the minus does.
+    fn write_min(&self, table: &PrecTable, follow: Option<i32>, out: &mut String) {
+        let swallows = follow.map(|p| p >= table.neg).unwrap_or(false);
+        match self {
+            Expr::Num(n) if *n < 0 && swallows => out.push_str(&format!("({})", n)),
+            Expr::Num(n) => out.push_str(&n.to_string()),
+            Expr::Decimal(d) => out.push_str(&d.to_string()),
+            Expr::Var(name) => out.push_str(name),
+            Expr::Neg(x) | Expr::Not(x) => {
+                if swallows {
+                    out.push('(');
+                }
+                out.push(if let Expr::Neg(_) = self { '-' } else { '!' });
+                let wrap = match x.as_ref() {
+                    Expr::Bin(op, _, _) => table.get(*op).0 < table.neg,
+                    Expr::Cond(..) => true,
+                    _ => false
+                };
+                x.write_child(table, wrap, if swallows { None } else { follow }, out);
+                if swallows {
+                    out.push(')');
+                }
+            }
+            Expr::Bin(op, a, b) => {
+                let (prec, assoc) = table.get(*op);
+                let wrap_a = match a.as_ref() {
+                    Expr::Bin(aop, _, _) => {
+                        let (ap, aa) = table.get(*aop);
+                        ap < prec || (ap == prec && aa == Assoc::Right)
+                    }
+                    Expr::Cond(..) => true,
+                    _ => false
+                };
+                let wrap_b = match b.as_ref() {
+                    Expr::Bin(bop, _, _) => {
+                        let bp = table.get(*bop).0;
+                        bp < prec || (bp == prec && assoc == Assoc::Left)
+                    }
+                    Expr::Cond(..) => true,
+                    _ => false
+                };
+                a.write_child(table, wrap_a, Some(prec), out);
+                out.push_str(&format!(" {} ", op.symbol()));
+                b.write_child(table, wrap_b, follow, out);
+            }
+            // The ternary binds loosest and to the right, so only a nested
+            // test needs brackets
+            Expr::Cond(c, a, b) => {
+                c.write_child(table, matches!(c.as_ref(), Expr::Cond(..)), None, out);
+                out.push_str(" ? ");
+                a.write_min(table, None, out);
+                out.push_str(" : ");
+                b.write_min(table, follow, out);
+            }
+            Expr::Call(name, args) => write_call(name, args, out, |a, out| a.write_min(table, None, out))
+        }
+    }
+
+    fn write_child(&self, table: &PrecTable, wrap: bool, follow: Option<i32>, out: &mut String) {
+        if wrap {
+            out.push('(');
+            self.write_min(table, None, out);
+            out.push(')');
+        } else {
+            self.write_min(table, follow, out);
+        }
+    }
+
+    fn write_full(&self, root: bool, out: &mut String) {
+        match self {
+            Expr::Num(n) if *n < 0 && !root => out.push_str(&format!("({})", n)),
+            Expr::Num(n) => out.push_str(&n.to_string()),
+            Expr::Decimal(d) => out.push_str(&d.to_string()),
+            Expr::Var(name) => out.push_str(name),
+            Expr::Neg(x) | Expr::Not(x) => {
+                if !root {
+                    out.push('(');
+                }
+                out.push(if let Expr::Neg(_) = self { '-' } else { '!' });
+                x.write_full(false, out);
+                if !root {
+                    out.push(')');
+                }
+            }
+            Expr::Bin(op, a, b) => {
+                if !root {
+                    out.push('(');
+                }
+                a.write_full(false, out);
+                out.push_str(&format!(" {} ", op.symbol()));
+                b.write_full(false, out);
+                if !root {
+                    out.push(')');
+                }
+            }
+            Expr::Cond(c, a, b) => {
+                if !root {
+                    out.push('(');
+                }
+                c.write_full(false, out);
+                out.push_str(" ? ");
+                a.write_full(false, out);
+                out.push_str(" : ");
+                b.write_full(false, out);
+                if !root {
+                    out.push(')');
+                }
+            }
+            Expr::Call(name, args) => write_call(name, args, out, |a, out| a.write_full(true, out))
+        }
+    }
+}
+
+// The arguments are bracketed already, so each is printed as a whole
+fn write_call<W: Fn(&Expr, &mut String)>(name: &str, args: &[Expr], out: &mut String, write: W) {
+    out.push_str(name);
+    out.push('(');
+    for (i, a) in args.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write(a, out);
+    }
+    out.push(')');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ParseError;
+    use crate::parser::{parse, MAX_DEPTH};
+    use crate::simplify::simplify;
+
+    fn build(expr: &str, table: &PrecTable) -> Expr {
+        Expr::build(&parse(expr).unwrap(), table).unwrap()
+    }
+
+    #[test]
+    fn test_grouping() {
+        let p1 = PrecTable::left_to_right();
+        let p2 = PrecTable::addition_first();
+        let std = PrecTable::standard();
+
+        assert_eq!(build("2 * 3 + 4", &p1).pretty_full(), "(2 * 3) + 4");
+        assert_eq!(build("2 * 3 + 4", &p2).pretty_full(), "2 * (3 + 4)");
+        assert_eq!(build("2 ^ 3 ^ 2", &std).pretty_full(), "2 ^ (3 ^ 2)");
+        assert_eq!(build("-2 ^ 2", &std).pretty_full(), "-(2 ^ 2)");
+        assert_eq!(build("-2 ^ 2", &p1).pretty_full(), "(-2) ^ 2");
+
+        assert_eq!(build("2 * 3 + 4", &p1).pretty(&p1), "2 * 3 + 4");
+        assert_eq!(build("2 * 3 + 4", &p2).pretty(&p2), "2 * 3 + 4");
+        assert_eq!(build("2 * 3 + 4", &p2).pretty(&p1), "2 * (3 + 4)");
+        assert_eq!(build("2 * (3 * 4)", &p1).pretty(&p1), "2 * (3 * 4)");
+        assert_eq!(build("(2 ^ 3) ^ 2", &std).pretty(&std), "(2 ^ 3) ^ 2");
+        assert_eq!(build("2 ^ (3 ^ 2)", &std).pretty(&std), "2 ^ 3 ^ 2");
+        assert_eq!(build("((1 + 2)) * 3", &std).pretty(&std), "(1 + 2) * 3");
+    }
+
+    #[test]
+    fn test_unary_minus_printing() {
+        let p1 = PrecTable::left_to_right();
+        let std = PrecTable::standard();
+
+        assert_eq!(build("(-2) ^ 2", &std).pretty(&std), "(-2) ^ 2");
+        assert_eq!(build("-(2 ^ 2)", &std).pretty(&std), "-2 ^ 2");
+        assert_eq!(build("-(2 + 3)", &std).pretty(&std), "-(2 + 3)");
+        assert_eq!(build("3 - -2 * 4", &std).pretty(&std), "3 - -2 * 4");
+        assert_eq!(build("-(2 ^ 2)", &std).pretty(&p1), "-(2 ^ 2)");
+        assert_eq!(build("(-2) ^ 2", &std).pretty(&p1), "-2 ^ 2");
+
+        // Negative literals follow the same rules as a unary minus
+        let e = Expr::Bin(Op::Pow, Box::new(Expr::Num(-2)), Box::new(Expr::Num(2)));
+        assert_eq!(e.pretty(&std), "(-2) ^ 2");
+        assert_eq!(e.pretty(&p1), "-2 ^ 2");
+        assert_eq!(e.pretty_full(), "(-2) ^ 2");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let tables = [PrecTable::left_to_right(), PrecTable::addition_first(), PrecTable::standard()];
+        let exprs = [
+            "2 * 3 + (4 * 5)",
+            "((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2",
+            "-(1 - 2) ^ -3 % (4 / -5)",
+            "2 ^ 3 ^ (2 - 1) * -(-4)",
+            "-a * (b + c) ^ d",
+            "max(-a, b ^ 2) * -abs(c - 1)",
+            "!a == b < c && -d || (e ? f : g) + 1",
+            "a ? b ? c : d : (e ? f : g) ? h : i",
+            "-(a ? b : c) * !(d && e) ^ 2",
+            "0.50 * -0.25 - 1.0"
+        ];
+
+        // Printing under any table and reparsing gives the same tree
+        for built_with in tables.iter() {
+            for e in exprs.iter() {
+                let ast = build(e, built_with);
+                for printed_with in tables.iter() {
+                    let s = ast.pretty(printed_with);
+                    assert_eq!(build(&s, printed_with), ast, "{} printed as {}", e, s);
+                }
+                let s = ast.pretty_full();
+                for t in tables.iter() {
+                    assert_eq!(build(&s, t), ast, "{} printed as {}", e, s);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_ast_eval() {
+        let p2 = PrecTable::addition_first();
+        let mut env: Env = Env::new();
+        assert_eq!(build("5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))", &p2).eval(&env), Ok(669060));
+        assert_eq!(build("2 * (3 - 3) / (1 - 1)", &p2).eval(&env), Err(EvalError::DivisionByZero));
+
+        let ast = build("x * 2 + y", &p2);
+        assert_eq!(ast.pretty(&p2), "x * 2 + y");
+        assert_eq!(ast.eval(&env), Err(EvalError::UndefinedVar("x".to_string())));
+        env.set("x", 3);
+        env.set("y", 4);
+        assert_eq!(ast.eval(&env), Ok(18));
+
+        let ast = build("gcd(x * 4, 2 + y) - 1", &p2);
+        assert_eq!(ast.pretty_full(), "gcd(x * 4, 2 + y) - 1");
+        assert_eq!(ast.eval(&env), Ok(5));
+
+        // Unneeded operands aren't evaluated
+        let ast = build("x > 5 && z || y == 4 ? !x : 1 / 0", &p2);
+        assert_eq!(ast.pretty_full(), "(((x > 5) && z) || (y == 4)) ? (!x) : (1 / 0)");
+        assert_eq!(ast.eval(&env), Ok(0));
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let std = PrecTable::standard();
+        let env: Env = Env::new();
+
+        // As deep as allowed still works, and can be walked on a test thread
+        let e = format!("{}1", "-".repeat(MAX_DEPTH - 1));
+        let ast = build(&e, &std);
+        assert_eq!(ast.eval(&env), Ok(-1));
+        assert_eq!(build(&ast.pretty(&std), &std), ast);
+        assert_eq!(simplify(&ast, &env), Expr::Num(-1));
+        let ast = build(&format!("x{}", " - x".repeat(MAX_DEPTH - 1)), &std);
+        assert_eq!(build(&ast.pretty(&std), &std), ast);
+        assert_eq!(simplify(&ast, &env).pretty(&std), format!("-{} * x", MAX_DEPTH - 2));
+
+        let e = format!("{}1", "-".repeat(MAX_DEPTH));
+        assert_eq!(Expr::build(&parse(&e).unwrap(), &std), Err(ParseError::TooDeep(Span::new(0, 1)).into()));
+
+        // Long chains are deep too, and are refused rather than overflowing
+        let n = 200_000;
+        let e = format!("1{}", " + 1".repeat(n));
+        assert!(matches!(Expr::build(&parse(&e).unwrap(), &std), Err(Error::Parse(ParseError::TooDeep(_)))));
+        let e = format!("1{}", " 1 +".repeat(n));
+        assert!(matches!(Expr::from_rpn(&e), Err(Error::Parse(ParseError::TooDeep(_)))));
+        let e = format!("{}1{}", "(".repeat(n), ")".repeat(n));
+        assert_eq!(build(&e, &std), Expr::Num(1));
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{channel, sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::error::Error;
+use crate::eval_num;
+use crate::lexer::Span;
+use crate::modular::{current_modulus, with_modulus};
+use crate::num::Number;
+use crate::prec::{Op, PrecTable};
+
+// A line that failed, kept with its text so it can be shown after the rest
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadLine {
+    pub line_no: usize,
+    pub src: String,
+    pub error: Error
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchReport<N> {
+    // Total of the lines that worked
+    pub sum: N,
+    pub errors: Vec<BadLine>
+}
+
+// (order, line number, text), and the same with the result once worked out
+type Job = (usize, usize, String);
+type Done<N> = (usize, usize, String, Result<N, Error>);
+
+// Evaluate each line of `reader` on its own, spread over `jobs` worker
+// threads. Lines are read as they are needed, so the input is never held in
+// memory at once, and `each` sees the results in input order. The total is
+// also built in input order, so an overflow is reported against the same
+// line as it would be by `sum_lines`. Lines share no variables, so `let` is
+// an error here.
+pub fn eval_batch<R, N, F>(reader: R, table: &PrecTable, jobs: usize, mut each: F) -> io::Result<BatchReport<N>>
+    where R: BufRead, N: Number + Send, F: FnMut(usize, &Result<N, Error>)
+{
+    assert!(jobs > 0, "Need at least one worker");
+    // Enough queued work to keep every worker busy without reading ahead
+    // further than that
+    let (job_tx, job_rx) = sync_channel::<Job>(jobs * 2);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (done_tx, done_rx) = channel::<Done<N>>();
+
+    // Workers carry on with any modulus set on this thread
+    let modulus = current_modulus();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let job_rx = Arc::clone(&job_rx);
+            let done_tx = done_tx.clone();
+            scope.spawn(move || {
+                let work = || worker(&job_rx, |(i, line_no, src)| {
+                    let result = eval_num::<N>(&src, table);
+                    // The receiver only goes away if this call is unwinding
+                    let _ = done_tx.send((i, line_no, src, result));
+                });
+                match modulus {
+                    Some(m) => with_modulus(m, work),
+                    None => work()
+                }
+            });
+        }
+        drop(done_tx);
+
+        let mut report = BatchReport { sum: N::from_i64(0), errors: Vec::new() };
+        // Results that came back ahead of an earlier line
+        let mut waiting = HashMap::new();
+        let mut next = 0;
+        let mut collect = |(i, line_no, src, result): Done<N>, report: &mut BatchReport<N>| {
+            waiting.insert(i, (line_no, src, result));
+            while let Some((line_no, src, result)) = waiting.remove(&next) {
+                each(line_no, &result);
+                let result = result.and_then(|n| Op::Add.apply(report.sum.clone(), n)
+                    .map_err(|e| Error::Eval(e, Span::new(0, src.len()))));
+                match result {
+                    Ok(total) => report.sum = total,
+                    Err(error) => report.errors.push(BadLine { line_no, src, error })
+                }
+                next += 1;
+            }
+        };
+
+        let mut sent = 0;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            job_tx.send((sent, i + 1, line.to_string())).expect("Workers stopped early");
+            sent += 1;
+            while let Ok(done) = done_rx.try_recv() {
+                collect(done, &mut report);
+            }
+        }
+        drop(job_tx);
+
+        for done in done_rx {
+            collect(done, &mut report);
+        }
+        Ok(report)
+    })
+}
+
+// Take jobs until the sender hangs up
+fn worker<F: FnMut(Job)>(jobs: &Mutex<Receiver<Job>>, mut f: F) {
+    loop {
+        let job = jobs.lock().expect("Worker panicked").recv();
+        match job {
+            Ok(job) => f(job),
+            Err(_) => return
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{EvalError, ParseError};
+    use crate::modular::Modular;
+    use crate::{eval_with, sum_lines};
+
+    const INPUT: &str = include_str!("../input.txt");
+
+    #[test]
+    fn test_batch_matches_sum_lines() {
+        let lines: Vec<(usize, &str)> = INPUT.lines().enumerate().map(|(i, l)| (i + 1, l.trim()))
+            .filter(|(_, l)| !l.is_empty()).collect();
+        for table in [PrecTable::left_to_right(), PrecTable::addition_first()].iter() {
+            let want = sum_lines(&lines, |s| eval_with(s, table).map(Some)).unwrap();
+            for &jobs in [1, 3, 8].iter() {
+                // Every line comes back, in order
+                let mut seen = Vec::new();
+                let report = eval_batch(INPUT.as_bytes(), table, jobs, |n, r: &Result<i64, Error>| {
+                    seen.push((n, r.clone()));
+                }).unwrap();
+                assert_eq!(report, BatchReport { sum: want, errors: vec![] });
+                let expected: Vec<(usize, Result<i64, Error>)> = lines.iter()
+                    .map(|&(n, l)| (n, eval_with(l, table))).collect();
+                assert_eq!(seen, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_batch_bad_lines() {
+        let src = "1 + 2\n\n3 * (4\n5 / 0\n9223372036854775807\n6\nlet x = 1\n";
+        let report: BatchReport<i64> = eval_batch(src.as_bytes(), &PrecTable::left_to_right(), 2, |_, _| ()).unwrap();
+        assert_eq!(report.sum, 9);
+        let bad: Vec<(usize, &str, Error)> = report.errors.iter()
+            .map(|b| (b.line_no, b.src.as_str(), b.error.clone())).collect();
+        assert_eq!(bad, vec![
+            (3, "3 * (4", Error::Parse(ParseError::UnclosedParen(Span::new(4, 5)))),
+            (4, "5 / 0", Error::Eval(EvalError::DivisionByZero, Span::new(2, 3))),
+            (5, "9223372036854775807", Error::Eval(EvalError::Overflow, Span::new(0, 19))),
+            (7, "let x = 1", Error::Parse(ParseError::UnexpectedToken(Span::new(0, 3))))
+        ]);
+    }
+
+    #[test]
+    fn test_batch_modular() {
+        // Workers use the modulus of the thread that started them
+        // 100 is 2 and 1 / 2 is 4, modulo 7
+        let src = "10 * 10\n1 / 2";
+        let report = with_modulus(7, || eval_batch::<_, Modular, _>(src.as_bytes(), &PrecTable::standard(), 2, |_, _| ()));
+        assert_eq!(report.unwrap().sum.value(), 6);
+    }
+}
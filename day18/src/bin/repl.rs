@@ -0,0 +1,48 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use day18::modular::{with_modulus, Modular};
+use day18::num::{Backend, Number};
+use day18::repl::Repl;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+
+// Read lines from stdin until it ends. The prompt is only shown to a person,
+// so piped scripts get just the results.
+fn run<N: Number>() {
+    let mut repl: Repl<N> = Repl::new();
+    let interactive = io::stdin().is_terminal();
+    let prompt = || {
+        if interactive {
+            print!("> ");
+            io::stdout().flush().expect("Couldn't write prompt");
+        }
+    };
+
+    prompt();
+    for line in io::stdin().lock().lines() {
+        let out = repl.line(&line.expect("Couldn't read stdin"));
+        if !out.is_empty() {
+            println!("{}", out);
+        }
+        prompt();
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    // Number type to evaluate with, as for the main binary
+    let backend: Backend = args.iter().position(|a| a == "--num")
+        .map(|i| args.get(i + 1).expect("--num requires a value"))
+        .map(|b| b.parse().unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or(Backend::I64);
+
+    match backend {
+        Backend::I64 => run::<i64>(),
+        Backend::I128 => run::<i128>(),
+        Backend::Big => run::<BigInt>(),
+        Backend::Rational => run::<BigRational>(),
+        Backend::F64 => run::<f64>(),
+        Backend::Modular(m) => with_modulus(m, run::<Modular>)
+    }
+}
@@ -0,0 +1,146 @@
+use std::marker::PhantomData;
+
+use crate::env::Env;
+use crate::error::{Error, EvalError};
+use crate::lexer::Span;
+use crate::num::{truthy, Decimal, Number};
+use crate::parser::{fold_expr, parse, DepthLimit, Fold};
+use crate::prec::{Op, PrecTable};
+
+type Eval<N> = Box<dyn Fn(&Env<N>) -> Result<N, Error>>;
+
+// An expression turned into nested closures once, so evaluating it again
+// doesn't revisit the tokens or the precedence table. Meant for running the
+// same expression over many environments. Evaluation recurses through the
+// closures, so nesting deeper than MAX_DEPTH is refused; leave that to
+// `eval_expr` or the VM.
+pub struct CompiledExpr<N> {
+    f: Eval<N>
+}
+
+impl<N> CompiledExpr<N> {
+    pub fn eval(&self, env: &Env<N>) -> Result<N, Error> {
+        (self.f)(env)
+    }
+}
+
+// Each fold builds the closure for its piece from the closures of its
+// operands. The number type is fixed up front since closures can't be generic.
+struct ClosureCompiler<N>(PhantomData<N>);
+
+fn at(span: Span) -> impl Fn(EvalError) -> Error {
+    move |e| Error::Eval(e, span)
+}
+
+impl<N: Number + 'static> Fold for ClosureCompiler<N> {
+    type Out = Eval<N>;
+
+    fn num(&self, n: i64, _: Span) -> Result<Eval<N>, Error> {
+        let n = N::from_i64(n);
+        Ok(Box::new(move |_| Ok(n.clone())))
+    }
+
+    // A bad literal only fails if it is reached, as when evaluating directly
+    fn decimal(&self, d: Decimal, span: Span) -> Result<Eval<N>, Error> {
+        let d = N::from_decimal(d).map_err(at(span));
+        Ok(Box::new(move |_| d.clone()))
+    }
+
+    fn var(&self, name: &str, span: Span) -> Result<Eval<N>, Error> {
+        let name = name.to_string();
+        Ok(Box::new(move |env| env.get(&name).ok_or_else(|| Error::Eval(EvalError::UndefinedVar(name.clone()), span))))
+    }
+
+    fn neg(&self, x: Eval<N>, span: Span) -> Result<Eval<N>, Error> {
+        Ok(Box::new(move |env| x(env)?.negate().map_err(at(span))))
+    }
+
+    fn not(&self, x: Eval<N>, _: Span) -> Result<Eval<N>, Error> {
+        Ok(Box::new(move |env| Ok(N::from_i64(!truthy(&x(env)?) as i64))))
+    }
+
+    // && and || only run the right side when the left doesn't decide
+    fn bin(&self, op: Op, a: Eval<N>, b: Eval<N>, span: Span) -> Result<Eval<N>, Error> {
+        Ok(match op {
+            Op::And | Op::Or => Box::new(move |env| {
+                let t = truthy(&a(env)?);
+                let t = if t == (op == Op::And) { truthy(&b(env)?) } else { t };
+                Ok(N::from_i64(t as i64))
+            }),
+            _ => Box::new(move |env| op.apply(a(env)?, b(env)?).map_err(at(span)))
+        })
+    }
+
+    fn cond(&self, c: Eval<N>, a: Eval<N>, b: Eval<N>, _: Span) -> Result<Eval<N>, Error> {
+        Ok(Box::new(move |env| if truthy(&c(env)?) { a(env) } else { b(env) }))
+    }
+
+    fn call(&self, name: &str, args: Vec<Eval<N>>, span: Span) -> Result<Eval<N>, Error> {
+        let name = name.to_string();
+        Ok(Box::new(move |env| {
+            let values = args.iter().map(|a| a(env)).collect::<Result<Vec<N>, Error>>()?;
+            env.call(&name, &values).map_err(at(span))
+        }))
+    }
+}
+
+// Parse and compile `expr`, grouped by `table`
+pub fn compile<N: Number + 'static>(expr: &str, table: &PrecTable) -> Result<CompiledExpr<N>, Error> {
+    let (f, _) = fold_expr(&parse(expr)?, table, &DepthLimit(ClosureCompiler(PhantomData)))?;
+    Ok(CompiledExpr { f })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ParseError;
+    use crate::eval_expr;
+    use crate::parser::MAX_DEPTH;
+
+    #[test]
+    fn test_closures_match_eval() {
+        let tables = [PrecTable::left_to_right(), PrecTable::addition_first(), PrecTable::standard()];
+        let env: Env = Env::new();
+        let exprs = include_str!("../input.txt").lines().filter(|l| !l.trim().is_empty())
+            .chain(vec!["-2 ^ 2 + max(3, abs(-7)) % 4", "1 / (2 - 2)", "!(1 < 2) || 3 >= 3 ? 4 : 5"]);
+        for e in exprs {
+            for table in tables.iter() {
+                let want = eval_expr(&parse(e).unwrap(), table, &env);
+                assert_eq!(compile::<i64>(e, table).unwrap().eval(&env), want, "{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_closures_reuse() {
+        let std = PrecTable::standard();
+        let f = compile::<i64>("x == 0 ? 0 : 100 / x + twice(y)", &std).unwrap();
+        let mut env: Env = Env::new();
+        env.define_fn("twice", 1, |args| Ok(args[0] * 2));
+        env.set("x", 0);
+        assert_eq!(f.eval(&env), Ok(0));
+        env.set("x", 7);
+        assert_eq!(f.eval(&env), Err(Error::Eval(EvalError::UndefinedVar("y".to_string()), Span::new(29, 30))));
+        env.set("y", 4);
+        assert_eq!(f.eval(&env), Ok(22));
+
+        // Decimals are only a problem if they're reached
+        let f = compile::<i64>("x || 0.5", &std).unwrap();
+        assert_eq!(f.eval(&env), Ok(1));
+        env.set("x", 0);
+        assert_eq!(f.eval(&env).unwrap_err().to_string(), "0.5 needs a number type that can hold fractions");
+        assert!(compile::<i64>("1 +", &std).is_err());
+    }
+
+    #[test]
+    fn test_closures_depth_limit() {
+        let std = PrecTable::standard();
+        let env: Env = Env::new();
+        let e = format!("1{}", " - 1".repeat(MAX_DEPTH - 1));
+        assert_eq!(compile::<i64>(&e, &std).unwrap().eval(&env), Ok(2 - MAX_DEPTH as i64));
+        let e = format!("1{}", " - 1".repeat(MAX_DEPTH));
+        assert_eq!(compile::<i64>(&e, &std).err(), Some(ParseError::TooDeep(Span::new(1998, 1999)).into()));
+        let e = format!("1{}", " + 1".repeat(200_000));
+        assert!(matches!(compile::<i64>(&e, &std), Err(Error::Parse(ParseError::TooDeep(_)))));
+    }
+}
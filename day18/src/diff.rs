@@ -0,0 +1,203 @@
+use std::fmt;
+
+use crate::ast::Expr;
+use crate::env::Env;
+use crate::prec::Op;
+use crate::simplify::simplify;
+
+// Both carry the offending expression and the variable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffError {
+    Unsupported(String, String),
+    VariableExponent(String, String)
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiffError::Unsupported(e, var) => write!(f, "can't differentiate {} with respect to {}", e, var),
+            DiffError::VariableExponent(e, var) => {
+                write!(f, "the exponent in {} depends on {}, which needs logarithms", e, var)
+            }
+        }
+    }
+}
+
+fn bin(op: Op, a: Expr, b: Expr) -> Expr {
+    Expr::Bin(op, Box::new(a), Box::new(b))
+}
+
+// Products and sums that skip the zeros and ones the rules below produce, so
+// the tree stays small before it is simplified
+fn mul(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (Expr::Num(0), _) | (_, Expr::Num(0)) => Expr::Num(0),
+        (Expr::Num(1), x) | (x, Expr::Num(1)) => x,
+        (a, b) => bin(Op::Mul, a, b)
+    }
+}
+
+fn add(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (Expr::Num(0), x) | (x, Expr::Num(0)) => x,
+        (a, b) => bin(Op::Add, a, b)
+    }
+}
+
+fn neg(x: Expr) -> Expr {
+    match x {
+        Expr::Num(0) => Expr::Num(0),
+        x => Expr::Neg(Box::new(x))
+    }
+}
+
+fn depends(expr: &Expr, var: &str) -> bool {
+    match expr {
+        Expr::Num(_) | Expr::Decimal(_) => false,
+        Expr::Var(name) => name == var,
+        Expr::Neg(x) | Expr::Not(x) => depends(x, var),
+        Expr::Bin(_, a, b) => depends(a, var) || depends(b, var),
+        Expr::Cond(c, a, b) => depends(c, var) || depends(a, var) || depends(b, var),
+        Expr::Call(_, args) => args.iter().any(|a| depends(a, var))
+    }
+}
+
+// a ^ b for b free of the variable
+fn power_rule(a: &Expr, b: &Expr, var: &str) -> Result<Expr, DiffError> {
+    let lower = bin(Op::Pow, a.clone(), bin(Op::Sub, b.clone(), Expr::Num(1)));
+    Ok(mul(mul(b.clone(), lower), derivative(a, var)?))
+}
+
+// The derivative of `expr` with respect to `var`, unsimplified. Comparisons
+// and logic are flat wherever they're defined, so they count as constants, and
+// a conditional or max/min/abs differentiates whichever side is taken. Other
+// variables are held fixed.
+pub fn derivative(expr: &Expr, var: &str) -> Result<Expr, DiffError> {
+    if !depends(expr, var) {
+        return Ok(Expr::Num(0));
+    }
+    let d = |e: &Expr| derivative(e, var);
+    let unsupported = || DiffError::Unsupported(expr.pretty_full(), var.to_string());
+    Ok(match expr {
+        Expr::Num(_) | Expr::Decimal(_) => Expr::Num(0),
+        Expr::Var(_) => Expr::Num(1),
+        Expr::Neg(x) => neg(d(x)?),
+        Expr::Not(_) => Expr::Num(0),
+        Expr::Bin(op, a, b) => match op {
+            Op::Add => add(d(a)?, d(b)?),
+            Op::Sub => add(d(a)?, neg(d(b)?)),
+            Op::Mul => add(mul(d(a)?, (**b).clone()), mul((**a).clone(), d(b)?)),
+            // a' / b when only the top depends on it, else the quotient rule
+            Op::Div if !depends(b, var) => bin(Op::Div, d(a)?, (**b).clone()),
+            Op::Div => {
+                let top = add(mul(d(a)?, (**b).clone()), neg(mul((**a).clone(), d(b)?)));
+                bin(Op::Div, top, bin(Op::Pow, (**b).clone(), Expr::Num(2)))
+            }
+            // Steps in a % b only happen where it isn't differentiable
+            Op::Mod if !depends(b, var) => d(a)?,
+            Op::Mod => return Err(unsupported()),
+            Op::Pow if depends(b, var) => {
+                return Err(DiffError::VariableExponent(expr.pretty_full(), var.to_string()));
+            }
+            Op::Pow => power_rule(a, b, var)?,
+            Op::Eq | Op::Lt | Op::Gt | Op::Le | Op::Ge | Op::And | Op::Or => Expr::Num(0)
+        },
+        Expr::Cond(c, a, b) => Expr::Cond(c.clone(), Box::new(d(a)?), Box::new(d(b)?)),
+        Expr::Call(name, args) => match (name.as_str(), args.as_slice()) {
+            ("max", [a, b]) | ("min", [a, b]) => {
+                let op = if name == "max" { Op::Ge } else { Op::Le };
+                Expr::Cond(Box::new(bin(op, a.clone(), b.clone())), Box::new(d(a)?), Box::new(d(b)?))
+            }
+            ("abs", [a]) => {
+                let da = d(a)?;
+                Expr::Cond(Box::new(bin(Op::Lt, a.clone(), Expr::Num(0))), Box::new(neg(da.clone())), Box::new(da))
+            }
+            ("pow", [_, b]) if depends(b, var) => {
+                return Err(DiffError::VariableExponent(expr.pretty_full(), var.to_string()));
+            }
+            ("pow", [a, b]) => power_rule(a, b, var)?,
+            // gcd and functions supplied by the host have no known derivative
+            _ => return Err(unsupported())
+        }
+    })
+}
+
+// The simplified derivative with respect to each of `vars`, in order
+pub fn gradient(expr: &Expr, vars: &[&str]) -> Result<Vec<Expr>, DiffError> {
+    let env: Env = Env::new();
+    vars.iter().map(|v| derivative(expr, v).map(|d| simplify(&d, &env))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::prec::PrecTable;
+
+    fn build(expr: &str) -> Expr {
+        Expr::build(&parse(expr).unwrap(), &PrecTable::standard()).unwrap()
+    }
+
+    fn d(expr: &str, var: &str) -> Result<String, String> {
+        let std = PrecTable::standard();
+        gradient(&build(expr), &[var])
+            .map(|g| g[0].pretty(&std))
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_derivatives() {
+        let ok = |s: &str| Ok(s.to_string());
+        assert_eq!(d("x ^ 3 + 2 * x * y - 7", "x"), ok("3 * x ^ 2 + 2 * y"));
+        assert_eq!(d("x ^ 3 + 2 * x * y - 7", "y"), ok("2 * x"));
+        assert_eq!(d("(x + 1) * (x - 1)", "x"), ok("2 * x"));
+        assert_eq!(d("-(x * x) + z", "x"), ok("-2 * x"));
+        assert_eq!(d("pow(x, 2) + x ^ n", "x"), ok("n * x ^ (n - 1) + 2 * x"));
+        assert_eq!(d("x / 2", "x"), ok("1 / 2"));
+        assert_eq!(d("1 / x", "x"), ok("-1 / x ^ 2"));
+        assert_eq!(d("x % 5 + (x > 1) + !x", "x"), ok("1"));
+        assert_eq!(d("max(x * x, 3)", "x"), ok("x ^ 2 >= 3 ? 2 * x : 0"));
+        assert_eq!(d("abs(x)", "x"), ok("x < 0 ? -1 : 1"));
+        assert_eq!(d("gcd(y, 4) * x", "x"), ok("gcd(y, 4)"));
+        assert_eq!(d("y * 3", "x"), ok("0"));
+    }
+
+    #[test]
+    fn test_not_differentiable() {
+        assert_eq!(d("2 ^ x", "x"), Err("the exponent in 2 ^ x depends on x, which needs logarithms".to_string()));
+        assert_eq!(d("pow(3, x * y)", "y"), Err("the exponent in pow(3, x * y) depends on y, which needs logarithms".to_string()));
+        assert_eq!(d("1 + gcd(x, 4)", "x"), Err("can't differentiate gcd(x, 4) with respect to x".to_string()));
+        assert_eq!(d("5 % x", "x"), Err("can't differentiate 5 % x with respect to x".to_string()));
+    }
+
+    #[test]
+    fn test_matches_finite_difference() {
+        // Evaluated at a point, each derivative agrees with a central difference
+        let exprs = [
+            "x ^ 3 - 4 * x * y + y / x",
+            "(x + y) * (x - 2) / (x * x + 1)",
+            "max(x, y) * abs(x - 3) + min(x * y, 2)",
+            "x > 1 ? pow(x, 4) : -x",
+            "1.5 * x ^ 2 + (x + y) ^ 3"
+        ];
+        let h = 1e-6;
+        for e in exprs.iter() {
+            let ast = build(e);
+            let grad = gradient(&ast, &["x", "y"]).unwrap();
+            for &(x, y) in [(1.7, 0.3), (-2.2, 4.1), (0.4, -1.3), (3.6, 2.5)].iter() {
+                let at = |x: f64, y: f64| {
+                    let mut env: Env<f64> = Env::new();
+                    env.set("x", x);
+                    env.set("y", y);
+                    env
+                };
+                let f = |x, y| ast.eval(&at(x, y)).unwrap();
+                let want = [(f(x + h, y) - f(x - h, y)) / (2.0 * h), (f(x, y + h) - f(x, y - h)) / (2.0 * h)];
+                for (g, want) in grad.iter().zip(want.iter()) {
+                    let got = g.eval(&at(x, y)).unwrap();
+                    assert!((got - want).abs() < 1e-4 * want.abs().max(1.0), "{} at ({}, {}): {} vs {}", e, x, y, got, want);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::error::EvalError;
+use crate::func::{builtin_arity, call_builtin, HostFn};
+use crate::num::Number;
+
+// Variable bindings and host functions, kept across lines
+#[derive(Clone)]
+pub struct Env<N = i64> {
+    vars: HashMap<String, N>,
+    funcs: HashMap<String, (usize, HostFn<N>)>
+}
+
+impl<N> Default for Env<N> {
+    fn default() -> Self {
+        Self { vars: HashMap::new(), funcs: HashMap::new() }
+    }
+}
+
+impl<N: fmt::Debug> fmt::Debug for Env<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Env")
+            .field("vars", &self.vars)
+            .field("funcs", &self.funcs.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<N: Clone> Env<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<N> {
+        self.vars.get(name).cloned()
+    }
+
+    pub fn set(&mut self, name: &str, value: N) {
+        self.vars.insert(name.to_string(), value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    // Make a function callable from expressions. Builtins can't be replaced
+    // since their argument counts are checked before the Env is seen.
+    pub fn define_fn<F>(&mut self, name: &str, arity: usize, f: F)
+        where F: Fn(&[N]) -> Result<N, EvalError> + 'static
+    {
+        assert!(builtin_arity(name).is_none(), "Can't redefine builtin function {}", name);
+        self.funcs.insert(name.to_string(), (arity, Rc::new(f)));
+    }
+
+    pub fn arity(&self, name: &str) -> Option<usize> {
+        builtin_arity(name).or_else(|| self.funcs.get(name).map(|(arity, _)| *arity))
+    }
+}
+
+impl<N: Number> Env<N> {
+    pub fn call(&self, name: &str, args: &[N]) -> Result<N, EvalError> {
+        let arity = self.arity(name).ok_or_else(|| EvalError::UnknownFunction(name.to_string()))?;
+        if arity != args.len() {
+            return Err(EvalError::WrongArity(name.to_string(), arity, args.len()));
+        }
+        match call_builtin(name, args) {
+            Some(result) => result,
+            None => (self.funcs[name].1)(args)
+        }
+    }
+}
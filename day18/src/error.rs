@@ -0,0 +1,199 @@
+use std::fmt;
+use crate::lexer::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    UnexpectedChar(char, Span),
+    NumberOverflow(Span),
+    UnclosedParen(Span),
+    UnmatchedParen(Span),
+    EmptyExpr(Span),
+    ExpectedTerm(Span),
+    ExpectedOperator(Span),
+    ExpectedIdent(Span),
+    ExpectedAssign(Span),
+    UnexpectedToken(Span),
+    MissingOperand(Span),
+    UnusedOperand(Span),
+    WrongArity(usize, usize, Span),
+    MissingElse(Span),
+    BadNumber(Span),
+    TooDeep(Span)
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedChar(_, s) => *s,
+            ParseError::NumberOverflow(s) => *s,
+            ParseError::UnclosedParen(s) => *s,
+            ParseError::UnmatchedParen(s) => *s,
+            ParseError::EmptyExpr(s) => *s,
+            ParseError::ExpectedTerm(s) => *s,
+            ParseError::ExpectedOperator(s) => *s,
+            ParseError::ExpectedIdent(s) => *s,
+            ParseError::ExpectedAssign(s) => *s,
+            ParseError::UnexpectedToken(s) => *s,
+            ParseError::MissingOperand(s) => *s,
+            ParseError::UnusedOperand(s) => *s,
+            ParseError::WrongArity(_, _, s) => *s,
+            ParseError::MissingElse(s) => *s,
+            ParseError::BadNumber(s) => *s,
+            ParseError::TooDeep(s) => *s
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedChar(c, _) => write!(f, "unexpected character '{}'", c),
+            ParseError::NumberOverflow(_) => write!(f, "number too large"),
+            ParseError::UnclosedParen(_) => write!(f, "unclosed '('"),
+            ParseError::UnmatchedParen(_) => write!(f, "unmatched ')'"),
+            ParseError::EmptyExpr(_) => write!(f, "empty expression"),
+            ParseError::ExpectedTerm(_) => write!(f, "expected a number, variable or '('"),
+            ParseError::ExpectedOperator(_) => write!(f, "expected an operator"),
+            ParseError::ExpectedIdent(_) => write!(f, "expected a variable name"),
+            ParseError::ExpectedAssign(_) => write!(f, "expected '='"),
+            ParseError::UnexpectedToken(_) => write!(f, "unexpected token"),
+            ParseError::MissingOperand(_) => write!(f, "operator is missing an operand"),
+            ParseError::UnusedOperand(_) => write!(f, "value is never used by an operator"),
+            ParseError::WrongArity(want, got, _) => write!(f, "function takes {} argument(s) but {} were given", want, got),
+            ParseError::MissingElse(_) => write!(f, "'?' without a matching ':'"),
+            ParseError::BadNumber(_) => write!(f, "malformed number"),
+            ParseError::TooDeep(_) => write!(f, "expression nested more than {} deep", crate::parser::MAX_DEPTH)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError {
+    DivisionByZero,
+    BadExponent(String),
+    Overflow,
+    UndefinedVar(String),
+    UnknownFunction(String),
+    WrongArity(String, usize, usize),
+    // The value and the modulus
+    NoInverse(u64, u64),
+    // An operand that needs its whole integer value, and the modulus it is
+    // only known to
+    NotExact(&'static str, u64),
+    Fraction(String),
+    // The expression a trace could take no further steps on
+    TraceStuck(String)
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+            EvalError::BadExponent(e) => write!(f, "exponent {} out of range", e),
+            EvalError::Overflow => write!(f, "arithmetic overflow"),
+            EvalError::UndefinedVar(name) => write!(f, "undefined variable '{}'", name),
+            EvalError::UnknownFunction(name) => write!(f, "unknown function '{}'", name),
+            EvalError::WrongArity(name, want, got) => {
+                write!(f, "function '{}' takes {} argument(s) but {} were given", name, want, got)
+            }
+            EvalError::NoInverse(n, m) => write!(f, "{} has no inverse modulo {}", n, m),
+            EvalError::NotExact(what, m) => write!(f, "{} is only known modulo {}, not as a whole number", what, m),
+            EvalError::Fraction(d) => write!(f, "{} needs a number type that can hold fractions", d),
+            EvalError::TraceStuck(e) => write!(f, "trace got stuck on {}", e)
+        }
+    }
+}
+
+// Any error from parsing or evaluating a line. Evaluation errors are tagged
+// with the span of the operator or variable that failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Parse(ParseError),
+    Eval(EvalError, Span)
+}
+
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            Error::Parse(e) => e.span(),
+            Error::Eval(_, s) => *s
+        }
+    }
+
+    // Render rustc style, with the source line and a caret under the span:
+    //
+    //   error: division by zero
+    //    --> line 3, column 8
+    //     |
+    //   3 | 2 * (3 / 0)
+    //     |        ^
+    pub fn render(&self, src: &str, line_no: usize) -> String {
+        let span = self.span();
+        let start = span.start.min(src.len());
+        let end = span.end.min(src.len());
+
+        // Columns count characters, not bytes
+        let col = src[..start].chars().count();
+        let width = src[start..end].chars().count().max(1);
+
+        let num = line_no.to_string();
+        let pad = " ".repeat(num.len());
+
+        format!("error: {}\n{}--> line {}, column {}\n{} |\n{} | {}\n{} | {}{}\n",
+            self, pad, line_no, col + 1,
+            pad,
+            num, src,
+            pad, " ".repeat(col), "^".repeat(width))
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(e) => e.fmt(f),
+            Error::Eval(e, _) => e.fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let err = Error::Eval(EvalError::DivisionByZero, Span::new(7, 8));
+        assert_eq!(err.render("2 * (3 / 0)", 3), "\
+error: division by zero
+ --> line 3, column 8
+  |
+3 | 2 * (3 / 0)
+  |        ^
+");
+
+        let err = Error::Parse(ParseError::EmptyExpr(Span::new(4, 7)));
+        assert_eq!(err.render("2 * ( )", 12), "\
+error: empty expression
+  --> line 12, column 5
+   |
+12 | 2 * ( )
+   |     ^^^
+");
+
+        // Zero width span at the end of the line still gets a caret
+        let err = Error::Parse(ParseError::ExpectedTerm(Span::new(8, 8)));
+        assert_eq!(err.render("é + 2 +", 1), "\
+error: expected a number, variable or '('
+ --> line 1, column 8
+  |
+1 | é + 2 +
+  |        ^
+");
+    }
+}
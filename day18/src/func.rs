@@ -0,0 +1,102 @@
+use std::rc::Rc;
+
+use crate::error::EvalError;
+use crate::num::Number;
+use crate::prec::Op;
+
+// Functions every expression can call, with how many arguments they take.
+// These are known while parsing, so a wrong argument count is a parse error.
+const BUILTINS: [(&str, usize); 5] = [
+    ("max", 2),
+    ("min", 2),
+    ("abs", 1),
+    ("gcd", 2),
+    ("pow", 2)
+];
+
+// A function supplied by the host program, see Env::define_fn
+pub type HostFn<N> = Rc<dyn Fn(&[N]) -> Result<N, EvalError>>;
+
+pub fn builtin_arity(name: &str) -> Option<usize> {
+    BUILTINS.iter().find(|(n, _)| *n == name).map(|(_, arity)| *arity)
+}
+
+fn abs<N: Number>(x: N) -> Result<N, EvalError> {
+    if x < N::from_i64(0) {
+        x.negate()
+    } else {
+        Ok(x)
+    }
+}
+
+// Euclid's algorithm, so it works for any backend with a remainder
+fn gcd<N: Number>(mut a: N, mut b: N) -> Result<N, EvalError> {
+    let zero = N::from_i64(0);
+    while b != zero {
+        let r = Op::Mod.apply(a, b.clone())?;
+        a = b;
+        b = r;
+    }
+    abs(a)
+}
+
+// Call a builtin, or None if there isn't one with this name. The argument
+// count must already have been checked.
+pub fn call_builtin<N: Number>(name: &str, args: &[N]) -> Option<Result<N, EvalError>> {
+    let arg = |i: usize| args[i].clone();
+    let result = match name {
+        "max" => Ok(if args[1] > args[0] { arg(1) } else { arg(0) }),
+        "min" => Ok(if args[1] < args[0] { arg(1) } else { arg(0) }),
+        "abs" => abs(arg(0)),
+        "gcd" => gcd(arg(0), arg(1)),
+        "pow" => Op::Pow.apply(arg(0), arg(1)),
+        _ => return None
+    };
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::Env;
+    use crate::error::{Error, ParseError};
+    use crate::lexer::Span;
+    use crate::prec::PrecTable;
+    use crate::{eval_expr, eval_num, exec_line};
+    use crate::parser::parse;
+
+    #[test]
+    fn test_builtins() {
+        let std = PrecTable::standard();
+        assert_eq!(eval_num::<i64>("max(2, 3) * min(4, -5)", &std), Ok(-15));
+        assert_eq!(eval_num::<i64>("abs(3 - 10) + gcd(12, -18)", &std), Ok(13));
+        assert_eq!(eval_num::<i64>("pow(2, max(3, 1) + 1)", &std), Ok(16));
+        assert_eq!(eval_num::<i64>("gcd(0, 0)", &std), Ok(0));
+        assert_eq!(eval_num::<f64>("max(abs(0 - 3 / 2), 1)", &std), Ok(1.5));
+
+        let err = |e| Err(Error::Parse(e));
+        assert_eq!(eval_num::<i64>("max(1)", &std), err(ParseError::WrongArity(2, 1, Span::new(0, 6))));
+        assert_eq!(eval_num::<i64>("1 + abs()", &std), err(ParseError::WrongArity(1, 0, Span::new(4, 9))));
+        assert_eq!(eval_num::<i64>("max(1, )", &std), err(ParseError::ExpectedTerm(Span::new(6, 6))));
+        assert_eq!(eval_num::<i64>("1, 2", &std), err(ParseError::UnexpectedToken(Span::new(1, 2))));
+        assert_eq!(eval_num::<i64>("(1, 2)", &std), err(ParseError::UnexpectedToken(Span::new(2, 3))));
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let std = PrecTable::standard();
+        let mut env: Env = Env::new();
+        env.define_fn("sum3", 3, |args| Ok(args.iter().sum()));
+        env.define_fn("answer", 0, |_| Ok(42));
+
+        assert_eq!(exec_line("let x = sum3(1, 2, 3) * answer()", &std, &mut env), Ok(None));
+        assert_eq!(env.get("x"), Some(252));
+
+        // Host functions aren't known until evaluation
+        let tokens = parse("sum3(1, 2)").unwrap();
+        assert_eq!(eval_expr(&tokens, &std, &env),
+            Err(Error::Eval(EvalError::WrongArity("sum3".to_string(), 3, 2), Span::new(0, 10))));
+        assert_eq!(eval_expr(&parse("nope(1)").unwrap(), &std, &env),
+            Err(Error::Eval(EvalError::UnknownFunction("nope".to_string()), Span::new(0, 7))));
+    }
+}
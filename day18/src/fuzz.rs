@@ -0,0 +1,276 @@
+// Differential tests over randomly generated expressions. The references
+// here don't share any code with the real evaluators: they rewrite the input
+// so plain left to right evaluation gives the intended answer.
+
+use std::convert::TryFrom;
+use std::panic::catch_unwind;
+
+use crate::ast::Expr;
+use crate::closure;
+use crate::env::Env;
+use crate::error::{Error, EvalError};
+use crate::parser::parse;
+use crate::prec::PrecTable;
+use crate::rpn::to_rpn_str;
+use crate::trace::{trace, trace_str};
+use crate::vm::compile_str;
+use crate::{eval_expr, eval_p1, eval_p2, eval_rpn, eval_with};
+
+// xorshift64, so runs are repeatable without pulling in a crate
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// A valid expression of + and * over small numbers, with brackets nested no
+// deeper than `depth`
+fn gen_expr(rng: &mut Rng, depth: usize) -> String {
+    let mut out = gen_term(rng, depth);
+    for _ in 0..rng.below(4) {
+        out.push_str(if rng.below(2) == 0 { " + " } else { " * " });
+        out.push_str(&gen_term(rng, depth));
+    }
+    out
+}
+
+fn gen_term(rng: &mut Rng, depth: usize) -> String {
+    if depth > 0 && rng.below(3) == 0 {
+        format!("({})", gen_expr(rng, depth - 1))
+    } else {
+        let most = if rng.below(4) == 0 { 99 } else { 9 };
+        (1 + rng.below(most)).to_string()
+    }
+}
+
+// Like gen_expr, but over logic and comparison operators too, with prefix
+// operators, variables and the odd division by zero in operands that may be
+// skipped
+fn gen_logic(rng: &mut Rng, depth: usize) -> String {
+    const OPS: [&str; 6] = [" + ", " * ", " / ", " && ", " || ", " == "];
+    let mut out = gen_logic_term(rng, depth);
+    for _ in 0..rng.below(5) {
+        out.push_str(OPS[rng.below(OPS.len())]);
+        out.push_str(&gen_logic_term(rng, depth));
+    }
+    out
+}
+
+fn gen_logic_term(rng: &mut Rng, depth: usize) -> String {
+    let prefix = ["", "", "-", "!"][rng.below(4)];
+    let term = match rng.below(5) {
+        0 if depth > 0 => format!("({})", gen_logic(rng, depth - 1)),
+        1 => ["x", "y"][rng.below(2)].to_string(),
+        _ => rng.below(4).to_string()
+    };
+    format!("{}{}", prefix, term)
+}
+
+// Bracket every operation so it happens in the order written, "1 + 2 * 3"
+// becoming "((1 + 2) * 3)"
+fn parenthesize(src: &str) -> String {
+    fn chain(chars: &[char], i: &mut usize) -> String {
+        let mut out = term(chars, i);
+        while *i < chars.len() && chars[*i] != ')' {
+            let op = chars[*i];
+            *i += 1;
+            out = format!("({} {} {})", out, op, term(chars, i));
+        }
+        out
+    }
+
+    fn term(chars: &[char], i: &mut usize) -> String {
+        if chars[*i] == '(' {
+            *i += 1;
+            let inner = chain(chars, i);
+            *i += 1;
+            inner
+        } else {
+            let start = *i;
+            while *i < chars.len() && chars[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            chars[start..*i].iter().collect()
+        }
+    }
+
+    let chars: Vec<char> = src.chars().filter(|c| !c.is_whitespace()).collect();
+    chain(&chars, &mut 0)
+}
+
+// Evaluate an expression where every operation is bracketed, in i128 so a
+// result too big for i64 still shows up as one. None means even i128
+// overflowed.
+fn eval_bracketed(src: &str) -> Option<i128> {
+    fn value(chars: &[u8], i: &mut usize) -> Option<i128> {
+        if chars[*i] == b'(' {
+            *i += 1;
+            let a = value(chars, i)?;
+            let op = chars[*i];
+            *i += 1;
+            let b = value(chars, i)?;
+            *i += 1;
+            if op == b'+' { a.checked_add(b) } else { a.checked_mul(b) }
+        } else {
+            let start = *i;
+            while *i < chars.len() && chars[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            std::str::from_utf8(&chars[start..*i]).ok()?.parse().ok()
+        }
+    }
+
+    let chars: Vec<u8> = src.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    value(&chars, &mut 0)
+}
+
+// Part 1 reference: strictly left to right
+fn reference_p1(src: &str) -> Result<i64, EvalError> {
+    eval_bracketed(&parenthesize(src))
+        .and_then(|n| i64::try_from(n).ok())
+        .ok_or(EvalError::Overflow)
+}
+
+// Part 2 reference: bracket the additions so they happen first. Splitting at
+// each '*' and bracketing the pieces does it, at every level.
+fn reference_p2(src: &str) -> Result<i64, EvalError> {
+    let src = format!("(({}))", src.replace('(', "((").replace(')', "))").replace('*', ") * ("));
+    reference_p1(&src)
+}
+
+// Only the kind of error matters, the span is checked elsewhere
+fn kind(result: Result<i64, Error>) -> Result<i64, EvalError> {
+    result.map_err(|e| match e {
+        Error::Eval(e, _) => e,
+        Error::Parse(e) => panic!("Valid expression failed to parse: {}", e)
+    })
+}
+
+#[test]
+fn test_fuzz_parts() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..2_000 {
+        let e = gen_expr(&mut rng, 4);
+        assert_eq!(kind(eval_p1(&e)), reference_p1(&e), "part 1: {}", e);
+        assert_eq!(kind(eval_p2(&e)), reference_p2(&e), "part 2: {}", e);
+    }
+}
+
+#[test]
+fn test_fuzz_evaluators_agree() {
+    // Every way of evaluating gives the same answer, under every table
+    let tables = [PrecTable::left_to_right(), PrecTable::addition_first(), PrecTable::standard()];
+    let env: Env = Env::new();
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..500 {
+        let e = gen_expr(&mut rng, 3);
+        for table in tables.iter() {
+            let want = eval_with(&e, table);
+            let ast = Expr::build(&parse(&e).unwrap(), table).unwrap();
+            assert_eq!(ast.eval(&env).ok(), want.clone().ok(), "ast: {}", e);
+            assert_eq!(compile_str(&e, table).unwrap().run(&[]), want, "vm: {}", e);
+            assert_eq!(closure::compile(&e, table).unwrap().eval(&env), want, "closures: {}", e);
+            assert_eq!(eval_rpn(&to_rpn_str(&e, table).unwrap(), &env).ok(), want.clone().ok(), "rpn: {}", e);
+            if let Ok(n) = want {
+                assert_eq!(trace_str::<i64>(&e, table).unwrap().last(), Some(&n.to_string()), "trace: {}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fuzz_trace_logic() {
+    // Tracing drops the same operands evaluating skips, even when a prefix
+    // operator binds looser than the logic operators around it
+    let tables = [PrecTable::standard(), "+:1:r,*:1:r,&&:5,||:6,==:3:r".parse().unwrap(),
+        "&&:1,||:1:r,==:2,neg:3".parse().unwrap()];
+    let mut env: Env = Env::new();
+    env.set("x", 2);
+    env.set("y", 1);
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..2000 {
+        let e = gen_logic(&mut rng, 2);
+        let tokens = parse(&e).unwrap();
+        for table in tables.iter() {
+            let want = eval_expr(&tokens, table, &env).map_err(|e| e.to_string());
+            let got = trace(&tokens, table, &env).map(|t| t.last().unwrap().parse::<i64>().unwrap())
+                .map_err(|e| e.to_string());
+            assert_eq!(got, want, "{} with {:?}", e, table);
+        }
+    }
+}
+
+// Damage a valid expression in a way that always leaves it invalid
+fn break_expr(rng: &mut Rng, e: &str) -> String {
+    let chars: Vec<char> = e.chars().collect();
+    let at = rng.below(chars.len() + 1);
+    let (before, after): (String, String) = (chars[..at].iter().collect(), chars[at..].iter().collect());
+    match rng.below(5) {
+        0 => format!("{}{}", e, [" +", " *", " (", ")", " 7"][rng.below(5)]),
+        1 => format!("{}(", before + &after),
+        2 => format!(")({}", e),
+        3 => format!("{} $ {}", before, after),
+        _ => format!("{} ()", e)
+    }
+}
+
+// Change one character to anything at all, which may or may not still be
+// valid
+fn mutate(rng: &mut Rng, e: &str) -> String {
+    let mut chars: Vec<char> = e.chars().collect();
+    let pick = ['(', ')', '+', '*', '-', '^', '!', '?', ':', ',', '0', 'x', ' ', '.', '_', 'é'];
+    let at = rng.below(chars.len());
+    match rng.below(3) {
+        0 => { chars.remove(at); }
+        1 => chars.insert(at, pick[rng.below(pick.len())]),
+        _ => chars[at] = pick[rng.below(pick.len())]
+    }
+    chars.into_iter().collect()
+}
+
+#[test]
+fn test_fuzz_malformed() {
+    let tables = [PrecTable::left_to_right(), PrecTable::addition_first(), PrecTable::standard()];
+    let mut rng = Rng(0xdead_beef_cafe_f00d);
+    for _ in 0..1_000 {
+        let e = gen_expr(&mut rng, 3);
+        let bad = break_expr(&mut rng, &e);
+        assert!(eval_p1(&bad).is_err(), "{} should not evaluate", bad);
+        assert!(eval_p2(&bad).is_err(), "{} should not evaluate", bad);
+
+        // Anything at all is reported as an error rather than a panic
+        let src = mutate(&mut rng, &e);
+        let outcome = catch_unwind(|| {
+            for table in tables.iter() {
+                let _ = eval_with(&src, table);
+                let _ = compile_str(&src, table).map(|p| p.run(&vec![1; p.vars().len()]));
+                let _ = closure::compile::<i64>(&src, table).map(|c| c.eval(&Env::new()));
+                let _ = trace_str::<i64>(&src, table);
+                let _ = to_rpn_str(&src, table);
+                if let Ok(tokens) = parse(&src) {
+                    let _ = Expr::build(&tokens, table);
+                }
+            }
+            let _ = eval_rpn::<i64>(&src, &Env::new());
+        });
+        assert!(outcome.is_ok(), "panicked on {:?}", src);
+    }
+}
+
+#[test]
+fn test_references() {
+    assert_eq!(parenthesize("1 + 2 * (3 + 4) * 5"), "(((1 + 2) * (3 + 4)) * 5)");
+    assert_eq!(reference_p1("1 + 2 * 3 + 4 * 5 + 6"), Ok(71));
+    assert_eq!(reference_p2("1 + 2 * 3 + 4 * 5 + 6"), Ok(231));
+    assert_eq!(reference_p2("((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2"), Ok(23340));
+    assert_eq!(reference_p1("99 * 99 * 99 * 99 * 99 * 99 * 99 * 99 * 99 * 99"), Err(EvalError::Overflow));
+}
@@ -0,0 +1,219 @@
+use crate::prec::Op;
+use crate::error::ParseError;
+use crate::num::Decimal;
+
+// Byte range [start, end) into the source line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    // Smallest span covering both
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tok {
+    Number(i64),
+    Decimal(Decimal),
+    Ident(String),
+    Op(Op),
+    LParen,
+    RParen,
+    Comma,
+    Not,
+    Question,
+    Colon,
+    Let,
+    Assign
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lexeme {
+    pub tok: Tok,
+    pub span: Span
+}
+
+pub fn lex(src: &str) -> Result<Vec<Lexeme>, ParseError> {
+    let mut out = Vec::new();
+    let mut chars = src.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let tok = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Tok::LParen,
+            ')' => Tok::RParen,
+            ',' => Tok::Comma,
+            '?' => Tok::Question,
+            ':' => Tok::Colon,
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut end = start + 1;
+                while let Some(&(i, d)) = chars.peek() {
+                    if !(d.is_ascii_alphanumeric() || d == '_') {
+                        break;
+                    }
+                    end = i + 1;
+                    chars.next();
+                }
+                let tok = match &src[start..end] {
+                    "let" => Tok::Let,
+                    name => Tok::Ident(name.to_string())
+                };
+                out.push(Lexeme { tok, span: Span::new(start, end) });
+                continue;
+            }
+            '0'..='9' => {
+                // Take everything that could belong to the number, so "0x1g"
+                // is one bad number rather than a number and a name
+                let mut end = start + 1;
+                while let Some(&(i, d)) = chars.peek() {
+                    let point = d == '.' && src[i + 1..].starts_with(|c: char| c.is_ascii_digit());
+                    if !(d.is_ascii_alphanumeric() || d == '_' || point) {
+                        break;
+                    }
+                    end = i + 1;
+                    chars.next();
+                }
+                let span = Span::new(start, end);
+                out.push(Lexeme { tok: number(&src[start..end], span)?, span });
+                continue;
+            }
+            _ => {
+                // Two character operators take priority, so "==" isn't "=" "="
+                if let Some(op) = src.get(start..start + 2).and_then(Op::from_symbol) {
+                    chars.next();
+                    out.push(Lexeme { tok: Tok::Op(op), span: Span::new(start, start + 2) });
+                    continue;
+                }
+                match (c, Op::from_symbol(&src[start..start + c.len_utf8()])) {
+                    (_, Some(op)) => Tok::Op(op),
+                    ('=', None) => Tok::Assign,
+                    ('!', None) => Tok::Not,
+                    _ => return Err(ParseError::UnexpectedChar(c, Span::new(start, start + c.len_utf8())))
+                }
+            }
+        };
+
+        out.push(Lexeme { tok, span: Span::new(start, start + c.len_utf8()) });
+    }
+
+    Ok(out)
+}
+
+// Parse a literal such as 42, 0xff, 0b1010, 0o17, 1_000 or 2.5. Digits can
+// be split up with '_' anywhere after the first.
+fn number(text: &str, span: Span) -> Result<Tok, ParseError> {
+    let (radix, body) = match text.get(..2) {
+        Some("0x") => (16, &text[2..]),
+        Some("0b") => (2, &text[2..]),
+        Some("0o") => (8, &text[2..]),
+        _ => (10, text)
+    };
+    let (int, frac) = match body.find('.') {
+        Some(p) if radix == 10 => (&body[..p], Some(&body[p + 1..])),
+        Some(_) => return Err(ParseError::BadNumber(span)),
+        None => (body, None)
+    };
+
+    let digits: String = int.chars().chain(frac.unwrap_or("").chars()).filter(|&c| c != '_').collect();
+    let valid = |part: &str| part.chars().any(|c| c != '_') && part.chars().all(|c| c == '_' || c.is_digit(radix));
+    if !valid(int) || !frac.map(valid).unwrap_or(true) {
+        return Err(ParseError::BadNumber(span));
+    }
+    let n = i64::from_str_radix(&digits, radix).map_err(|_| ParseError::NumberOverflow(span))?;
+
+    Ok(match frac {
+        Some(frac) => Tok::Decimal(Decimal { digits: n, scale: frac.chars().filter(|&c| c != '_').count() as u32 }),
+        None => Tok::Number(n)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lex_spans() {
+        let lexemes = lex("12 *(3+ 45)").unwrap();
+        let toks: Vec<(Tok, usize, usize)> = lexemes.iter()
+            .map(|l| (l.tok.clone(), l.span.start, l.span.end))
+            .collect();
+        assert_eq!(toks, vec![
+            (Tok::Number(12), 0, 2),
+            (Tok::Op(Op::Mul), 3, 4),
+            (Tok::LParen, 4, 5),
+            (Tok::Number(3), 5, 6),
+            (Tok::Op(Op::Add), 6, 7),
+            (Tok::Number(45), 8, 10),
+            (Tok::RParen, 10, 11)
+        ]);
+    }
+
+    #[test]
+    fn test_lex_idents() {
+        let toks: Vec<Tok> = lex("let x_1=lettuce*_").unwrap().into_iter().map(|l| l.tok).collect();
+        assert_eq!(toks, vec![
+            Tok::Let,
+            Tok::Ident("x_1".to_string()),
+            Tok::Assign,
+            Tok::Ident("lettuce".to_string()),
+            Tok::Op(Op::Mul),
+            Tok::Ident("_".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_lex_logic() {
+        let toks: Vec<Tok> = lex("a<=b==!c?1:x>=2||y<3&&z").unwrap().into_iter().map(|l| l.tok).collect();
+        let ident = |s: &str| Tok::Ident(s.to_string());
+        assert_eq!(toks, vec![
+            ident("a"), Tok::Op(Op::Le), ident("b"), Tok::Op(Op::Eq), Tok::Not, ident("c"),
+            Tok::Question, Tok::Number(1), Tok::Colon, ident("x"), Tok::Op(Op::Ge), Tok::Number(2),
+            Tok::Op(Op::Or), ident("y"), Tok::Op(Op::Lt), Tok::Number(3), Tok::Op(Op::And), ident("z")
+        ]);
+        assert_eq!(lex("1 = = 2").unwrap()[1].tok, Tok::Assign);
+        assert_eq!(lex("1 | 2"), Err(ParseError::UnexpectedChar('|', Span::new(2, 3))));
+    }
+
+    #[test]
+    fn test_lex_errors() {
+        assert_eq!(lex("2 & 3"), Err(ParseError::UnexpectedChar('&', Span::new(2, 3))));
+        assert_eq!(lex("1 + é"), Err(ParseError::UnexpectedChar('é', Span::new(4, 6))));
+        assert_eq!(lex("99999999999999999999"), Err(ParseError::NumberOverflow(Span::new(0, 20))));
+    }
+
+    #[test]
+    fn test_lex_numbers() {
+        let tok = |s: &str| lex(s).map(|l| l[0].tok.clone());
+        let dec = |digits, scale| Ok(Tok::Decimal(Decimal { digits, scale }));
+        assert_eq!(tok("0xff"), Ok(Tok::Number(255)));
+        assert_eq!(tok("0b1010"), Ok(Tok::Number(10)));
+        assert_eq!(tok("0o17"), Ok(Tok::Number(15)));
+        assert_eq!(tok("1_000_000"), Ok(Tok::Number(1_000_000)));
+        assert_eq!(tok("0x_dead_BEEF"), Ok(Tok::Number(0xdead_beef)));
+        assert_eq!(tok("007"), Ok(Tok::Number(7)));
+        assert_eq!(tok("2.5"), dec(25, 1));
+        assert_eq!(tok("0.0_25"), dec(25, 3));
+        assert_eq!(tok("0x7fff_ffff_ffff_ffff"), Ok(Tok::Number(i64::MAX)));
+
+        let bad = |s: &str| Err(ParseError::BadNumber(Span::new(0, s.len())));
+        for s in ["0x", "0b102", "0o8", "12abc", "0x1.5", "0xg", "0b_"].iter() {
+            assert_eq!(tok(s), bad(s), "{}", s);
+        }
+        assert_eq!(tok("0x8000_0000_0000_0000"), Err(ParseError::NumberOverflow(Span::new(0, 21))));
+        assert_eq!(tok("0.99999999999999999999"), Err(ParseError::NumberOverflow(Span::new(0, 22))));
+
+        // A point not followed by a digit isn't part of the number
+        assert_eq!(lex("1."), Err(ParseError::UnexpectedChar('.', Span::new(1, 2))));
+        assert_eq!(lex("1._5"), Err(ParseError::UnexpectedChar('.', Span::new(1, 2))));
+        assert_eq!(lex("1.5.2"), Err(ParseError::BadNumber(Span::new(0, 5))));
+    }
+}
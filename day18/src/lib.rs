@@ -0,0 +1,365 @@
+pub mod lexer;
+pub mod error;
+pub mod prec;
+pub mod parser;
+pub mod ast;
+pub mod env;
+pub mod func;
+pub mod vm;
+pub mod closure;
+pub mod num;
+pub mod modular;
+pub mod rpn;
+pub mod simplify;
+pub mod trace;
+pub mod repl;
+pub mod batch;
+pub mod diff;
+#[cfg(test)]
+mod fuzz;
+
+use std::cell::RefCell;
+
+use env::Env;
+use error::{Error, EvalError};
+use lexer::Span;
+use num::{truthy, Decimal, Number};
+use parser::{fold_expr, parse, parse_stmt, Fold, Guard, Stmt, Token};
+use prec::{Op, PrecTable};
+
+struct Evaluator<'e, N> {
+    env: &'e Env<N>,
+    // One entry per open guard: whether its operand is being skipped
+    skip: RefCell<Vec<bool>>
+}
+
+impl<'e, N: Number> Evaluator<'e, N> {
+    fn new(env: &'e Env<N>) -> Self {
+        Self { env, skip: RefCell::new(Vec::new()) }
+    }
+
+    // Run `f` unless this is an operand that isn't needed, which just gives a
+    // placeholder so errors in it don't surface
+    fn live<F: FnOnce() -> Result<N, EvalError>>(&self, span: Span, f: F) -> Result<N, Error> {
+        if self.skip.borrow().last() == Some(&true) {
+            return Ok(N::from_i64(0));
+        }
+        f().map_err(|e| Error::Eval(e, span))
+    }
+}
+
+impl<'e, N: Number> Fold for Evaluator<'e, N> {
+    type Out = N;
+
+    fn num(&self, n: i64, span: Span) -> Result<N, Error> {
+        self.live(span, || Ok(N::from_i64(n)))
+    }
+
+    fn decimal(&self, d: Decimal, span: Span) -> Result<N, Error> {
+        self.live(span, || N::from_decimal(d))
+    }
+
+    fn var(&self, name: &str, span: Span) -> Result<N, Error> {
+        self.live(span, || self.env.get(name).ok_or_else(|| EvalError::UndefinedVar(name.to_string())))
+    }
+
+    fn neg(&self, x: N, span: Span) -> Result<N, Error> {
+        self.live(span, || x.negate())
+    }
+
+    fn not(&self, x: N, span: Span) -> Result<N, Error> {
+        self.live(span, || Ok(N::from_i64(!truthy(&x) as i64)))
+    }
+
+    // A skipped operand of && or || is never the one that decides the result
+    fn bin(&self, op: Op, a: N, b: N, span: Span) -> Result<N, Error> {
+        self.live(span, || op.apply(a, b))
+    }
+
+    fn cond(&self, c: N, a: N, b: N, span: Span) -> Result<N, Error> {
+        self.live(span, || Ok(if truthy(&c) { a } else { b }))
+    }
+
+    fn call(&self, name: &str, args: Vec<N>, span: Span) -> Result<N, Error> {
+        self.live(span, || self.env.call(name, &args))
+    }
+
+    fn guard(&self, kind: Guard, test: &N) {
+        let t = truthy(test);
+        let unneeded = match kind {
+            Guard::And | Guard::Then => !t,
+            Guard::Or | Guard::Else => t
+        };
+        let mut skip = self.skip.borrow_mut();
+        let outer = skip.last() == Some(&true);
+        skip.push(outer || unneeded);
+    }
+
+    fn unguard(&self) {
+        self.skip.borrow_mut().pop();
+    }
+}
+
+pub fn eval_expr<N: Number>(tokens: &[Token], table: &PrecTable, env: &Env<N>) -> Result<N, Error> {
+    fold_expr(tokens, table, &Evaluator::new(env))
+}
+
+// Evaluate an expression written in RPN
+pub fn eval_rpn<N: Number>(src: &str, env: &Env<N>) -> Result<N, Error> {
+    rpn::fold_rpn(src, |name| env.arity(name), &Evaluator::new(env))
+}
+
+// Evaluate a lone expression with no variables bound, using the number type N
+pub fn eval_num<N: Number>(expr: &str, table: &PrecTable) -> Result<N, Error> {
+    let tokens = parse(expr)?;
+    eval_expr(&tokens, table, &Env::new())
+}
+
+pub fn eval_with(expr: &str, table: &PrecTable) -> Result<i64, Error> {
+    eval_num(expr, table)
+}
+
+// Run one line against the environment. A `let` binds its value and gives
+// nothing back, a bare expression gives its value.
+pub fn exec_line<N: Number>(line: &str, table: &PrecTable, env: &mut Env<N>) -> Result<Option<N>, Error> {
+    match parse_stmt(line)? {
+        Stmt::Let(name, _, tokens) => {
+            let value = eval_expr(&tokens, table, env)?;
+            env.set(&name, value);
+            Ok(None)
+        }
+        Stmt::Expr(tokens) => Ok(Some(eval_expr(&tokens, table, env)?))
+    }
+}
+
+pub fn eval_p1(expr: &str) -> Result<i64, Error> {
+    eval_with(expr, &PrecTable::left_to_right())
+}
+
+pub fn eval_p2(expr: &str) -> Result<i64, Error> {
+    eval_with(expr, &PrecTable::addition_first())
+}
+
+// Evaluate and sum every line that gives a value, carrying on past failures.
+// Lines are paired with their line number so errors can be reported against
+// the file. The running total is checked too; if adding a line overflows it,
+// that line is reported.
+pub fn sum_lines<N, F>(lines: &[(usize, &str)], mut eval: F) -> Result<N, Vec<(usize, Error)>>
+    where N: Number, F: FnMut(&str) -> Result<Option<N>, Error>
+{
+    let mut sum = N::from_i64(0);
+    let mut errors = Vec::new();
+
+    for &(line_no, line) in lines {
+        match eval(line) {
+            Ok(Some(n)) => match Op::Add.apply(sum.clone(), n) {
+                Ok(total) => sum = total,
+                Err(e) => errors.push((line_no, Error::Eval(e, Span::new(0, line.len()))))
+            },
+            Ok(None) => (),
+            Err(e) => errors.push((line_no, e))
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(sum)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::{EvalError, ParseError};
+
+    #[test]
+    fn test_eval_p1() {
+        assert_eq!(eval_p1("2 * 3 + (4 * 5)"), Ok(26));
+        assert_eq!(eval_p1("5 + (8 * 3 + 9 + 3 * 4 * 3)"), Ok(437));
+        assert_eq!(eval_p1("5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))"), Ok(12240));
+        assert_eq!(eval_p1("((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2"), Ok(13632));
+    }
+
+    #[test]
+    fn test_eval_p2() {
+        assert_eq!(eval_p2("2 * 3 + (4 * 5)"), Ok(46));
+        assert_eq!(eval_p2("5 + (8 * 3 + 9 + 3 * 4 * 3)"), Ok(1445));
+        assert_eq!(eval_p2("5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))"), Ok(669060));
+        assert_eq!(eval_p2("((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2"), Ok(23340));
+    }
+
+    #[test]
+    fn test_custom_prec_table() {
+        // Conventional maths ordering
+        let table: PrecTable = "*:2,+:1".parse().unwrap();
+        assert_eq!(eval_with("2 * 3 + (4 * 5)", &table), Ok(26));
+        assert_eq!(eval_with("2 + 3 * 4", &table), Ok(14));
+
+        // Right associative at a single level evaluates right to left
+        let table: PrecTable = "+:1:r,*:1:r".parse().unwrap();
+        assert_eq!(eval_with("2 * 3 + 4", &table), Ok(14));
+    }
+
+    #[test]
+    fn test_extended_operators() {
+        let std = PrecTable::standard();
+        assert_eq!(eval_with("10 - 2 * 3", &std), Ok(4));
+        assert_eq!(eval_with("7 / 2 + 7 % 2", &std), Ok(4));
+        assert_eq!(eval_with("2 ^ 3 ^ 2", &std), Ok(512));
+        assert_eq!(eval_with("-2 ^ 2", &std), Ok(-4));
+        assert_eq!(eval_with("(-2) ^ 2", &std), Ok(4));
+        assert_eq!(eval_with("3 - -2", &std), Ok(5));
+        assert_eq!(eval_with("--3", &std), Ok(3));
+
+        assert_eq!(eval_p1("10 - 2 * 3"), Ok(24));
+        assert_eq!(eval_p1("2 ^ 3 ^ 2"), Ok(64));
+        assert_eq!(eval_p1("-2 ^ 2"), Ok(4));
+        assert_eq!(eval_p1("2 * -3 + 1"), Ok(-5));
+
+        assert_eq!(eval_p2("10 / 2 + 3"), Ok(2));
+        assert_eq!(eval_p2("10 % 4 - 1"), Ok(1));
+        assert_eq!(eval_p2("2 * 3 - 1"), Ok(4));
+        assert_eq!(eval_p2("-2 ^ 2 + 1"), Ok(-3));
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        assert_eq!(eval_p1("1 / 0"), Err(Error::Eval(EvalError::DivisionByZero, Span::new(2, 3))));
+        assert_eq!(eval_p2("5 % (2 - 2)"), Err(Error::Eval(EvalError::DivisionByZero, Span::new(2, 3))));
+        assert_eq!(eval_with("2 ^ -1", &PrecTable::standard()),
+            Err(Error::Eval(EvalError::BadExponent("-1".to_string()), Span::new(2, 3))));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |e| Err(Error::Parse(e));
+        assert_eq!(eval_p1("2 - 3 $ 4"), err(ParseError::UnexpectedChar('$', Span::new(6, 7))));
+        assert_eq!(eval_p1("(2 + 3"), err(ParseError::UnclosedParen(Span::new(0, 1))));
+        assert_eq!(eval_p1("2 + (3 * (4)"), err(ParseError::UnclosedParen(Span::new(4, 5))));
+        assert_eq!(eval_p1("2 + 3) * 4"), err(ParseError::UnmatchedParen(Span::new(5, 6))));
+        assert_eq!(eval_p1(""), err(ParseError::EmptyExpr(Span::new(0, 0))));
+        assert_eq!(eval_p1("   "), err(ParseError::EmptyExpr(Span::new(0, 3))));
+        assert_eq!(eval_p1("2 * ( )"), err(ParseError::EmptyExpr(Span::new(4, 7))));
+        assert_eq!(eval_p1("2 +"), err(ParseError::ExpectedTerm(Span::new(3, 3))));
+        assert_eq!(eval_p1("2 * (3 +) + 1"), err(ParseError::ExpectedTerm(Span::new(8, 8))));
+        assert_eq!(eval_p1("* 3"), err(ParseError::ExpectedTerm(Span::new(0, 1))));
+        assert_eq!(eval_p2("2 + + 3"), err(ParseError::ExpectedTerm(Span::new(4, 5))));
+        assert_eq!(eval_p2("2 3"), err(ParseError::ExpectedOperator(Span::new(2, 3))));
+        assert_eq!(eval_p2("2 * (1) (4 + 5)"), err(ParseError::ExpectedOperator(Span::new(8, 15))));
+        assert_eq!(eval_p1("1 ? 2"), err(ParseError::MissingElse(Span::new(2, 3))));
+        assert_eq!(eval_p1("(1 ? 2) : 3"), err(ParseError::MissingElse(Span::new(3, 4))));
+        assert_eq!(eval_p1("1 : 2"), err(ParseError::UnexpectedToken(Span::new(2, 3))));
+        assert_eq!(eval_p1("1 ? : 2"), err(ParseError::ExpectedTerm(Span::new(4, 5))));
+        assert_eq!(eval_p1("!"), err(ParseError::ExpectedTerm(Span::new(1, 1))));
+    }
+
+    #[test]
+    fn test_logic_operators() {
+        let std = PrecTable::standard();
+        assert_eq!(eval_with("1 + 2 == 3", &std), Ok(1));
+        assert_eq!(eval_with("2 < 1 || 3 >= 3 && !(4 <= 3)", &std), Ok(1));
+        assert_eq!(eval_with("5 > 2 == 1", &std), Ok(1));
+        assert_eq!(eval_with("2 && 3", &std), Ok(1));
+        assert_eq!(eval_with("!0 + !7", &std), Ok(1));
+        assert_eq!(eval_with("1 ? 2 : 0 ? 3 : 4", &std), Ok(2));
+        assert_eq!(eval_with("0 ? 2 : 0 ? 3 : 4", &std), Ok(4));
+        assert_eq!(eval_with("(1 ? 0 : 1) ? 3 : 4", &std), Ok(4));
+        assert_eq!(eval_with("1 ? 0 ? 5 : 6 : 7", &std), Ok(6));
+        assert_eq!(eval_with("max(1 < 2 ? 3 : 4, 0) * 2", &std), Ok(6));
+
+        // The operand that isn't needed is never evaluated
+        assert_eq!(eval_with("0 && 1 / 0", &std), Ok(0));
+        assert_eq!(eval_with("1 || y", &std), Ok(1));
+        assert_eq!(eval_with("0 ? 1 / 0 : 2", &std), Ok(2));
+        assert_eq!(eval_with("1 ? 2 : abs(1 / 0 && 1 / 0)", &std), Ok(2));
+        assert_eq!(eval_with("1 && 1 / 0", &std), Err(Error::Eval(EvalError::DivisionByZero, Span::new(7, 8))));
+
+        let mut env: Env = Env::new();
+        env.set("x", 0);
+        assert_eq!(exec_line("x == 0 ? 0 : 10 / x", &std, &mut env), Ok(Some(0)));
+        env.set("x", 5);
+        assert_eq!(exec_line("x == 0 ? 0 : 10 / x", &std, &mut env), Ok(Some(2)));
+
+        // Comparisons sit below the arithmetic in every preset
+        assert_eq!(eval_p1("1 + 1 == 2 * 1"), Ok(1));
+        assert_eq!(eval_p2("3 * 2 < 2 + 5"), Ok(1));
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let depth = 1_000_000;
+        let nest = |open: &str, inner: &str| format!("{}{}{}", open.repeat(depth), inner, ")".repeat(depth));
+
+        assert_eq!(eval_p1(&nest("(", "7")), Ok(7));
+        assert_eq!(eval_p2(&nest("1 + (", "1")), Ok(depth as i64 + 1));
+        assert_eq!(eval_p1(&nest("-(", "3")), Ok(3));
+        assert_eq!(eval_p1(&nest("(", "")), Err(Error::Parse(ParseError::EmptyExpr(Span::new(depth - 1, depth + 1)))));
+
+        // Long chains, including a right associative one
+        let chain = vec!["1"; depth].join(" + ");
+        assert_eq!(eval_p2(&chain), Ok(depth as i64));
+        let chain = vec!["1"; depth].join(" ^ ");
+        assert_eq!(eval_with(&chain, &PrecTable::standard()), Ok(1));
+        assert_eq!(vm::compile_str(&chain, &PrecTable::standard()).unwrap().run(&[]), Ok(1));
+    }
+
+    #[test]
+    fn test_sum_lines() {
+        let lines = vec![(1, "1 + 2"), (2, "3 * (4"), (4, "5 / 0"), (5, "6")];
+        let errors = sum_lines(&lines, |s| eval_p1(s).map(Some)).unwrap_err();
+        assert_eq!(errors, vec![
+            (2, Error::Parse(ParseError::UnclosedParen(Span::new(4, 5)))),
+            (4, Error::Eval(EvalError::DivisionByZero, Span::new(2, 3)))
+        ]);
+        assert_eq!(sum_lines(&lines[..1], |s| eval_p1(s).map(Some)), Ok(3));
+    }
+
+    #[test]
+    fn test_variables() {
+        let p2 = PrecTable::addition_first();
+        let mut env: Env = Env::new();
+
+        assert_eq!(exec_line("let x = 2 * 3", &p2, &mut env), Ok(None));
+        assert_eq!(exec_line("let y = x + 1", &p2, &mut env), Ok(None));
+        assert_eq!(exec_line("x * y + 2", &p2, &mut env), Ok(Some(54)));
+
+        // Rebinding sees the old value
+        assert_eq!(exec_line("let x = x * x", &p2, &mut env), Ok(None));
+        assert_eq!(env.get("x"), Some(36));
+
+        assert_eq!(exec_line("1 + z", &p2, &mut env),
+            Err(Error::Eval(EvalError::UndefinedVar("z".to_string()), Span::new(4, 5))));
+        assert_eq!(eval_p1("x"), Err(Error::Eval(EvalError::UndefinedVar("x".to_string()), Span::new(0, 1))));
+
+        // A failed let leaves the environment alone
+        assert!(exec_line("let y = 1 / 0", &p2, &mut env).is_err());
+        assert_eq!(env.get("y"), Some(7));
+    }
+
+    #[test]
+    fn test_program() {
+        let lines = vec![
+            (1, "let a = 4"),
+            (2, "let b = a * 2 + 1"),
+            (3, "a + b"),
+            (4, "c"),
+            (5, "b * 2")
+        ];
+        let mut env: Env = Env::new();
+        let table = PrecTable::left_to_right();
+        let errors = sum_lines(&lines, |s| exec_line(s, &table, &mut env)).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 4);
+
+        let mut env: Env = Env::new();
+        let ok: Vec<(usize, &str)> = lines.into_iter().filter(|(n, _)| *n != 4).collect();
+        assert_eq!(sum_lines(&ok, |s| exec_line(s, &table, &mut env)), Ok(13 + 18));
+        assert_eq!(env.len(), 2);
+
+        // Overflowing the total is reported against the line that did it
+        let lines = vec![(1, "9223372036854775807"), (2, "1"), (3, "0 - 1")];
+        let errors = sum_lines::<i64, _>(&lines, |s| eval_p1(s).map(Some)).unwrap_err();
+        assert_eq!(errors, vec![(2, Error::Eval(EvalError::Overflow, Span::new(0, 1)))]);
+    }
+}
@@ -1,137 +1,203 @@
-use regex::Regex;
-use itertools::Itertools;
-
-#[derive(Debug)]
-enum Token {
-    Number(i64),
-    Add,
-    Mul,
-    SubExpr(Vec<Token>)
-}
-
-fn expr_token_chomp<'a>(tokens: &mut dyn Iterator<Item = &'a str>) -> Vec<Token> {
-    let mut out = Vec::new();
-
-    while let Some(t) = tokens.next() {
-        out.push(match t {
-            "+" => Token::Add,
-            "*" => Token::Mul,
-            "(" => Token::SubExpr(expr_token_chomp(tokens)),
-            ")" => return out,
-            _ => Token::Number(t.parse::<i64>()
-                    .expect("Expected + - ( ) or a number!"))
-        });
+use day18::{eval_rpn, exec_line, sum_lines};
+use day18::batch::eval_batch;
+use day18::ast::Expr;
+use day18::env::Env;
+use day18::error::Error;
+use day18::modular::{with_modulus, Modular};
+use day18::num::{Backend, Number};
+use day18::parser::parse;
+use day18::prec::PrecTable;
+use day18::rpn::to_rpn;
+use day18::simplify::simplify;
+use day18::trace::trace;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+
+fn report<N: Number>(name: &str, lines: &[(usize, &str)], result: Result<N, Vec<(usize, Error)>>) {
+    match result {
+        Ok(sum) => println!("{} = {}", name, sum),
+        Err(errors) => {
+            for (line_no, e) in &errors {
+                let src = lines.iter().find(|(n, _)| n == line_no).map(|(_, s)| *s).unwrap_or("");
+                eprintln!("{}", e.render(src, *line_no));
+            }
+            println!("{} failed: {} bad line(s)", name, errors.len());
+        }
     }
-
-    return out;
 }
 
-fn parse(expr: &str) -> Vec<Token> {
-    let token_pat = r"([0-9]+|\+|\*|\(|\))";
-    let token_pat = Regex::new(token_pat).expect("Pattern compile failed");
-
-    let mut tokens = token_pat.find_iter(expr)
-        .map(|m| m.as_str());
-    
-    return expr_token_chomp(&mut tokens);
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter().position(|a| a == name)
+        .map(|i| args.get(i + 1).unwrap_or_else(|| panic!("{} requires a value", name)).as_str())
 }
 
-fn eval_term(token: &Token) -> i64 {
-    match token {
-        Token::Number(n) => *n,
-        Token::SubExpr(ex) => eval_expr(ex),
-        _ => panic!("Expected term (number or sub expression)")
+// Show how a single expression is grouped under each table
+fn show_ast<N: Number>(expr: &str, tables: &[(&str, PrecTable)]) {
+    let tokens = match parse(expr) {
+        Ok(tokens) => tokens,
+        Err(e) => return eprintln!("{}", Error::from(e).render(expr, 1))
+    };
+
+    for (name, table) in tables {
+        match Expr::build(&tokens, table) {
+            Ok(ast) => match ast.eval(&Env::<N>::new()) {
+                Ok(n) => println!("{}: {} = {}", name, ast.pretty_full(), n),
+                Err(e) => println!("{}: {} = error: {}", name, ast.pretty_full(), e)
+            },
+            Err(e) => eprintln!("{}", e.render(expr, 1))
+        }
     }
 }
 
-fn eval_expr(tokens: &Vec<Token>) -> i64 {
-    let mut acc = eval_term(tokens.first().expect("Must evaluate at least 1 token"));
-
-    for (op, next_t) in tokens.iter().skip(1).tuples() {
-        acc = match op {
-            Token::Add => acc + eval_term(next_t),
-            Token::Mul => acc * eval_term(next_t),
-            _ => panic!("Expected operator")
+// Convert an infix expression to RPN under each table
+fn show_rpn(expr: &str, tables: &[(&str, PrecTable)]) {
+    let tokens = match parse(expr) {
+        Ok(tokens) => tokens,
+        Err(e) => return eprintln!("{}", Error::from(e).render(expr, 1))
+    };
+
+    for (name, table) in tables {
+        match to_rpn(&tokens, table) {
+            Ok(rpn) => println!("{}: {}", name, rpn),
+            Err(e) => eprintln!("{}", e.render(expr, 1))
         }
     }
+}
 
-    return acc;
+// Simplify an expression with unknowns, as grouped by each table
+fn show_simplified(expr: &str, tables: &[(&str, PrecTable)]) {
+    let tokens = match parse(expr) {
+        Ok(tokens) => tokens,
+        Err(e) => return eprintln!("{}", Error::from(e).render(expr, 1))
+    };
+
+    for (name, table) in tables {
+        match Expr::build(&tokens, table) {
+            Ok(ast) => println!("{}: {}", name, simplify(&ast, &Env::new()).pretty(table)),
+            Err(e) => eprintln!("{}", e.render(expr, 1))
+        }
+    }
 }
 
-fn eval_p1(expr: &str) -> i64 {
-    let tokens = parse(expr);
-    return eval_expr(&tokens);
+// Print each step of evaluating an expression under each table
+fn show_trace<N: Number>(expr: &str, tables: &[(&str, PrecTable)]) {
+    let tokens = match parse(expr) {
+        Ok(tokens) => tokens,
+        Err(e) => return eprintln!("{}", Error::from(e).render(expr, 1))
+    };
+
+    for (name, table) in tables {
+        println!("{}: {}", name, expr);
+        match trace(&tokens, table, &Env::<N>::new()) {
+            Ok(steps) => steps.iter().for_each(|step| println!("  = {}", step)),
+            Err(e) => eprintln!("{}", e.render(expr, 1))
+        }
+    }
 }
 
-// Evaluate a term, but reduce the additions first
-fn reduce_add_term(token: &Token) -> i64 {
-    match token {
-        Token::Number(n) => *n,
-        Token::SubExpr(ex) => reduce_additions(ex),
-        _ => panic!("Expected term (number or sub expression)")
+// Evaluate RPN input, showing it back in infix
+fn show_from_rpn<N: Number>(src: &str) {
+    let result = Expr::from_rpn(src)
+        .and_then(|ast| eval_rpn(src, &Env::<N>::new()).map(|n| (ast, n)));
+    match result {
+        Ok((ast, n)) => println!("{} = {}", ast.pretty_full(), n),
+        Err(e) => eprintln!("{}", e.render(src, 1))
     }
 }
 
-// Reduce additions first and then perform normal evaluation
-fn reduce_additions(tokens: &Vec<Token>) -> i64 {
-    let mut out = Vec::new();
-    let mut acc = reduce_add_term(tokens.first().expect("Must evaluate at least 1 token"));
-
-    for (op, next_t) in tokens.iter().skip(1).tuples() {
-        match op {
-            Token::Mul => {
-                out.push(Token::Number(acc));
-                out.push(Token::Mul);
-                acc = reduce_add_term(next_t);
+// Stream input.txt through worker threads, printing each line's value in
+// order and any bad lines once the whole file has been read
+fn run_batch<N: Number + Send>(jobs: usize, tables: &[(&str, PrecTable)]) {
+    for (name, table) in tables {
+        let file = std::fs::File::open("input.txt").expect("Couldn't open file");
+        let result = eval_batch(std::io::BufReader::new(file), table, jobs, |line_no, value: &Result<N, Error>| {
+            if let Ok(n) = value {
+                println!("{} line {}: {}", name, line_no, n);
             }
-            Token::Add => {
-                acc += reduce_add_term(next_t);
-            }
-            _ => panic!("Expected operator!")
+        });
+        let report = result.expect("Couldn't read file");
+        for bad in &report.errors {
+            eprintln!("{}", bad.error.render(&bad.src, bad.line_no));
+        }
+        match report.errors.len() {
+            0 => println!("{} = {}", name, report.sum),
+            n => println!("{} = {} without {} bad line(s)", name, report.sum, n)
         }
     }
-
-    out.push(Token::Number(acc));
-
-    return eval_expr(&out);
-}
-
-fn eval_p2(expr: &str) -> i64 {
-    let tokens = parse(expr);
-    return reduce_additions(&tokens);
 }
 
 fn main() {
-    let contents = std::fs::read_to_string("input.txt").expect("Couldn't read file");
-    let lines: Vec<&str> = contents.split("\n")
-        .map(|s| s.trim())
-        .filter(|s| s.len() > 0)
-        .collect();
-
-    let p1: i64 = lines.iter().map(|s| eval_p1(s)).sum();
-    println!("Part 1 = {}", p1);
+    let args: Vec<String> = std::env::args().collect();
+
+    // Optionally evaluate with a custom ordering, e.g. --prec "*:2,+:1"
+    let custom: Option<PrecTable> = arg_value(&args, "--prec")
+        .map(|spec| spec.parse().unwrap_or_else(|e| panic!("{}", e)));
+
+    let mut tables = vec![
+        ("Part 1", PrecTable::left_to_right()),
+        ("Part 2", PrecTable::addition_first())
+    ];
+    if let Some(table) = custom {
+        tables.push(("Custom", table));
+    }
 
-    let p2: i64 = lines.iter().map(|s| eval_p2(s)).sum();
-    println!("Part 2 = {}", p2);
+    // Number type to evaluate with, e.g. --num rational, or --num mod:97 to
+    // work modulo 97
+    let backend: Backend = arg_value(&args, "--num")
+        .map(|b| b.parse().unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or(Backend::I64);
+
+    match backend {
+        Backend::I64 => run::<i64>(&args, &tables),
+        Backend::I128 => run::<i128>(&args, &tables),
+        Backend::Big => run::<BigInt>(&args, &tables),
+        Backend::Rational => run::<BigRational>(&args, &tables),
+        Backend::F64 => run::<f64>(&args, &tables),
+        Backend::Modular(m) => with_modulus(m, || run::<Modular>(&args, &tables))
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn run<N: Number + Send>(args: &[String], tables: &[(&str, PrecTable)]) {
+    if let Some(expr) = arg_value(args, "--ast") {
+        show_ast::<N>(expr, tables);
+        return;
+    }
+    if let Some(expr) = arg_value(args, "--rpn") {
+        show_rpn(expr, tables);
+        return;
+    }
+    if let Some(expr) = arg_value(args, "--simplify") {
+        show_simplified(expr, tables);
+        return;
+    }
+    if let Some(expr) = arg_value(args, "--trace") {
+        show_trace::<N>(expr, tables);
+        return;
+    }
+    if let Some(src) = arg_value(args, "--from-rpn") {
+        show_from_rpn::<N>(src);
+        return;
+    }
 
-    #[test]
-    fn test_eval_p1() {
-        assert_eq!(eval_p1("2 * 3 + (4 * 5)"), 26);
-        assert_eq!(eval_p1("5 + (8 * 3 + 9 + 3 * 4 * 3)"), 437);
-        assert_eq!(eval_p1("5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))"), 12240);
-        assert_eq!(eval_p1("((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2"), 13632);
+    // Evaluate lines independently on this many threads, e.g. --jobs 8
+    if let Some(jobs) = arg_value(args, "--jobs") {
+        let jobs = jobs.parse().ok().filter(|&n| n > 0).expect("--jobs needs a positive number");
+        run_batch::<N>(jobs, tables);
+        return;
     }
 
-    #[test]
-    fn test_eval_p2() {
-        assert_eq!(eval_p2("2 * 3 + (4 * 5)"), 46);
-        assert_eq!(eval_p2("5 + (8 * 3 + 9 + 3 * 4 * 3)"), 1445);
-        assert_eq!(eval_p2("5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))"), 669060);
-        assert_eq!(eval_p2("((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2"), 23340);
+    let contents = std::fs::read_to_string("input.txt").expect("Couldn't read file");
+    let lines: Vec<(usize, &str)> = contents.split('\n')
+        .map(|s| s.trim())
+        .enumerate()
+        .map(|(i, s)| (i + 1, s))
+        .filter(|(_, s)| !s.is_empty())
+        .collect();
+
+    // Each part runs the file as a program, so `let` lines bind variables for
+    // the lines after them
+    for (name, table) in tables {
+        let mut env = Env::<N>::new();
+        report(name, &lines, sum_lines(&lines, |s| exec_line(s, table, &mut env)));
     }
 }
@@ -0,0 +1,284 @@
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use crate::error::EvalError;
+use crate::num::Number;
+use crate::prec::Op;
+
+thread_local! {
+    static MODULUS: Cell<u64> = const { Cell::new(0) };
+}
+
+// Integers modulo m, reduced after every operation so they never overflow.
+// The modulus is set for a whole evaluation with `with_modulus`, since values
+// are made from plain literals that don't know it. The whole integer is kept
+// alongside for as long as it is known and fits, since exponents and % need
+// it rather than the residue. Values compare by residue alone.
+#[derive(Debug, Clone, Copy)]
+pub struct Modular {
+    value: u64,
+    exact: Option<i64>
+}
+
+// Run `f` with arithmetic modulo `m`, putting back any outer modulus after
+pub fn with_modulus<T, F: FnOnce() -> T>(m: u64, f: F) -> T {
+    assert!(m > 0, "Modulus must be at least 1");
+    let outer = MODULUS.with(|c| c.replace(m));
+    let out = f();
+    MODULUS.with(|c| c.set(outer));
+    out
+}
+
+// The modulus set on this thread, if any, so work handed to other threads
+// can use it too
+pub fn current_modulus() -> Option<u64> {
+    Some(MODULUS.with(|c| c.get())).filter(|&m| m > 0)
+}
+
+fn modulus() -> u64 {
+    let m = MODULUS.with(|c| c.get());
+    assert!(m > 0, "Modular arithmetic used outside with_modulus");
+    m
+}
+
+impl Modular {
+    // The representative in 0..m
+    pub fn value(self) -> u64 {
+        self.value
+    }
+
+    fn reduce(n: i128) -> Self {
+        Modular { value: n.rem_euclid(modulus() as i128) as u64, exact: i64::try_from(n).ok() }
+    }
+
+    // A residue whose integer value isn't known
+    fn residue(value: u64) -> Self {
+        Modular { value, exact: None }
+    }
+
+    fn exact(self, what: &'static str) -> Result<i64, EvalError> {
+        self.exact.ok_or_else(|| EvalError::NotExact(what, modulus()))
+    }
+
+    // x such that self * x is 1, found with the extended Euclidean algorithm
+    fn inverse(self) -> Result<Self, EvalError> {
+        let m = modulus() as i128;
+        let (mut r0, mut r1) = (m, self.value as i128);
+        let (mut t0, mut t1) = (0i128, 1i128);
+        while r1 != 0 {
+            let q = r0 / r1;
+            let (r, t) = (r0 - q * r1, t0 - q * t1);
+            r0 = r1;
+            r1 = r;
+            t0 = t1;
+            t1 = t;
+        }
+        if r0 != 1 {
+            return Err(EvalError::NoInverse(self.value, m as u64));
+        }
+        Ok(Modular::residue(t0.rem_euclid(m) as u64))
+    }
+
+    fn pow(self, mut e: u64) -> Self {
+        let m = modulus() as u128;
+        let (mut base, mut out) = (self.value as u128, 1 % m);
+        while e > 0 {
+            if e & 1 == 1 {
+                out = out * base % m;
+            }
+            base = base * base % m;
+            e >>= 1;
+        }
+        Modular::residue(out as u64)
+    }
+}
+
+impl PartialEq for Modular {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl Eq for Modular {}
+
+impl PartialOrd for Modular {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Modular {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value.cmp(&other.value)
+    }
+}
+
+impl Hash for Modular {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value.hash(state);
+    }
+}
+
+impl fmt::Display for Modular {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.value, f)
+    }
+}
+
+// Division multiplies by the inverse. ^ raises to the whole exponent, using
+// the inverse when it is negative, and % is the remainder of the whole
+// numbers, so both fail once a value is only known modulo m, as after a
+// division.
+impl Number for Modular {
+    fn from_i64(n: i64) -> Self {
+        Modular::reduce(n as i128)
+    }
+
+    fn negate(self) -> Result<Self, EvalError> {
+        Ok(match self.exact {
+            Some(n) => Modular::reduce(-(n as i128)),
+            None => Modular::residue(Modular::reduce(-(self.value as i128)).value)
+        })
+    }
+
+    fn apply(op: Op, a: Self, b: Self) -> Result<Self, EvalError> {
+        // The result's whole value, when both sides have one
+        let exact = |f: fn(i128, i128) -> i128| match (a.exact, b.exact) {
+            (Some(x), Some(y)) => Modular::reduce(f(x as i128, y as i128)),
+            _ => Modular::residue(Modular::reduce(f(a.value as i128, b.value as i128)).value)
+        };
+        match op {
+            Op::Add => Ok(exact(|x, y| x + y)),
+            Op::Sub => Ok(exact(|x, y| x - y)),
+            Op::Mul => {
+                let m = modulus() as u128;
+                let value = (a.value as u128 * b.value as u128 % m) as u64;
+                let whole = a.exact.zip(b.exact).and_then(|(x, y)| x.checked_mul(y));
+                Ok(Modular { value, exact: whole })
+            }
+            Op::Div => Ok(Modular::residue(Modular::apply(Op::Mul, a, b.inverse()?)?.value)),
+            Op::Mod => {
+                let (x, y) = (a.exact("left side of %")?, b.exact("right side of %")?);
+                match y {
+                    0 => Err(EvalError::DivisionByZero),
+                    y => Ok(Modular::reduce(x as i128 % y as i128))
+                }
+            }
+            Op::Pow => {
+                let e = b.exact("exponent")?;
+                let base = if e < 0 { a.inverse()? } else { a };
+                let power = base.pow(e.unsigned_abs());
+                let whole = a.exact.zip(u32::try_from(e).ok()).and_then(|(x, e)| x.checked_pow(e));
+                Ok(Modular { value: power.value, exact: whole })
+            }
+            _ => unreachable!("{:?} is handled by Op::apply", op)
+        }
+    }
+}
+
+// Parse a modulus from the command line
+pub fn parse_modulus(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(m) if m > 0 => Ok(m),
+        _ => Err(format!("Bad modulus '{}', expected a whole number of at least 1", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigInt;
+
+    use crate::env::Env;
+    use crate::prec::PrecTable;
+    use crate::{eval_num, exec_line, sum_lines};
+
+    const P: u64 = 1_000_000_007;
+
+    fn eval(expr: &str, m: u64) -> Result<u64, String> {
+        with_modulus(m, || eval_num::<Modular>(expr, &PrecTable::standard()))
+            .map(Modular::value)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_modular() {
+        assert_eq!(eval("5 + 4", 7), Ok(2));
+        assert_eq!(eval("2 - 5", 7), Ok(4));
+        assert_eq!(eval("-1", 7), Ok(6));
+        assert_eq!(eval("3 ^ 6", 7), Ok(1));
+        assert_eq!(eval("9 % 4", 7), Ok(1));
+        assert_eq!(eval("-9 % 4 + 7", 7), Ok(6));
+        assert_eq!(eval("8 == 1", 7), Ok(1));
+        assert_eq!(eval("5 + 4", 1), Ok(0));
+
+        // Big products never overflow
+        let e = "9223372036854775807 * 9223372036854775807 * 9223372036854775807";
+        let want = BigInt::from(i64::MAX).pow(3) % P;
+        assert_eq!(eval(e, P).map(BigInt::from), Ok(want));
+        assert_eq!(eval("2 ^ 1000000006", P), Ok(1));
+        assert_eq!(eval("-1 * -1", u64::MAX), Ok(1));
+    }
+
+    #[test]
+    fn test_modular_pow() {
+        // The exponent is the whole number, not its residue
+        assert_eq!(eval("2 ^ 7", 7), Ok(2));
+        assert_eq!(eval("3 ^ 10", 7), Ok(4));
+        assert_eq!(eval("3 ^ (5 + 5)", 7), Ok(4));
+        assert_eq!(eval("2 ^ 2 ^ 3", 7), Ok(4));
+        assert_eq!(eval("5 ^ 0", 1), Ok(0));
+        // Negative exponents go through the inverse
+        assert_eq!(eval("2 ^ -1", 7), Ok(4));
+        assert_eq!(eval("3 ^ -2 * 9", 7), Ok(1));
+        assert_eq!(eval("3 ^ -1", 9), Err("3 has no inverse modulo 9".to_string()));
+
+        // Products too big to know exactly still reduce correctly as bases
+        let big = "9223372036854775807 * 9223372036854775807";
+        let want = BigInt::from(i64::MAX).pow(6) % P;
+        assert_eq!(eval(&format!("({}) ^ 3", big), P).map(BigInt::from), Ok(want));
+    }
+
+    #[test]
+    fn test_modular_not_exact() {
+        // Only a residue is known after dividing, or once the value overflows
+        let err = |what: &str| Err(format!("{} is only known modulo 7, not as a whole number", what));
+        assert_eq!(eval("2 ^ (1 / 3)", 7), err("exponent"));
+        assert_eq!(eval("(1 / 3) % 2", 7), err("left side of %"));
+        assert_eq!(eval("5 % (9223372036854775807 + 1)", 7), err("right side of %"));
+        assert_eq!(eval("(6 / 3) * 3", 7), Ok(6));
+    }
+
+    #[test]
+    fn test_modular_division() {
+        assert_eq!(eval("1 / 3", 7), Ok(5));
+        assert_eq!(eval("6 / 4 * 4", 7), Ok(6));
+        assert_eq!(eval("1 / 2 + 1 / 2", P), Ok(1));
+        assert_eq!(eval("3 / 6", 9), Err("6 has no inverse modulo 9".to_string()));
+        assert_eq!(eval("1 / 0", 7), Err("0 has no inverse modulo 7".to_string()));
+        assert_eq!(eval("1 % 7", 7), Ok(1));
+        assert_eq!(eval("1 % (7 - 7)", 7), Err("division by zero".to_string()));
+    }
+
+    #[test]
+    fn test_modular_sum() {
+        // The total is reduced too
+        let lines = vec![(1, "let x = 4"), (2, "x * 2"), (3, "x ^ 2")];
+        let mut env: Env<Modular> = Env::new();
+        let table = PrecTable::left_to_right();
+        let sum = with_modulus(5, || sum_lines(&lines, |s| exec_line(s, &table, &mut env)));
+        assert_eq!(sum.map(Modular::value), Ok(4));
+
+        // Agrees with reducing the exact answer
+        let p2 = PrecTable::addition_first();
+        for e in include_str!("../input.txt").lines().filter(|l| !l.trim().is_empty()) {
+            let exact = eval_num::<BigInt>(e, &p2).unwrap();
+            let m = 1_000_003;
+            let got = with_modulus(m, || eval_num::<Modular>(e, &p2)).unwrap();
+            assert_eq!(BigInt::from(got.value()), exact % m, "{}", e);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive, Zero};
+
+use crate::error::EvalError;
+use crate::prec::Op;
+
+// Arithmetic the evaluator can run over. Every operation reports problems
+// (overflow, division by zero, ...) instead of panicking or wrapping.
+pub trait Number: Clone + PartialOrd + fmt::Debug + fmt::Display {
+    fn from_i64(n: i64) -> Self;
+    fn negate(self) -> Result<Self, EvalError>;
+    fn apply(op: Op, a: Self, b: Self) -> Result<Self, EvalError>;
+
+    // Only types that can hold fractions take decimal literals
+    fn from_decimal(d: Decimal) -> Result<Self, EvalError> {
+        Err(EvalError::Fraction(d.to_string()))
+    }
+}
+
+// A literal with a fractional part: `digits` / 10^`scale`, so 1.25 is 125
+// with scale 2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Decimal {
+    pub digits: i64,
+    pub scale: u32
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = format!("{:0>width$}", self.digits, width = self.scale as usize + 1);
+        let (int, frac) = s.split_at(s.len() - self.scale as usize);
+        write!(f, "{}.{}", int, frac)
+    }
+}
+
+// Anything but zero counts as true
+pub fn truthy<N: Number>(x: &N) -> bool {
+    *x != N::from_i64(0)
+}
+
+// Fixed width integers, erroring on overflow
+macro_rules! checked_int {
+    ($t:ty) => {
+        impl Number for $t {
+            fn from_i64(n: i64) -> Self {
+                n as $t
+            }
+
+            fn negate(self) -> Result<Self, EvalError> {
+                self.checked_neg().ok_or(EvalError::Overflow)
+            }
+
+            fn apply(op: Op, a: Self, b: Self) -> Result<Self, EvalError> {
+                match op {
+                    Op::Add => a.checked_add(b).ok_or(EvalError::Overflow),
+                    Op::Sub => a.checked_sub(b).ok_or(EvalError::Overflow),
+                    Op::Mul => a.checked_mul(b).ok_or(EvalError::Overflow),
+                    Op::Div | Op::Mod if b == 0 => Err(EvalError::DivisionByZero),
+                    Op::Div => a.checked_div(b).ok_or(EvalError::Overflow),
+                    Op::Mod => a.checked_rem(b).ok_or(EvalError::Overflow),
+                    Op::Pow => {
+                        let e = u32::try_from(b).map_err(|_| EvalError::BadExponent(b.to_string()))?;
+                        a.checked_pow(e).ok_or(EvalError::Overflow)
+                    }
+                    _ => unreachable!("{:?} is handled by Op::apply", op)
+                }
+            }
+        }
+    };
+}
+
+checked_int!(i64);
+checked_int!(i128);
+
+impl Number for BigInt {
+    fn from_i64(n: i64) -> Self {
+        BigInt::from(n)
+    }
+
+    fn negate(self) -> Result<Self, EvalError> {
+        Ok(-self)
+    }
+
+    fn apply(op: Op, a: Self, b: Self) -> Result<Self, EvalError> {
+        match op {
+            Op::Add => Ok(a + b),
+            Op::Sub => Ok(a - b),
+            Op::Mul => Ok(a * b),
+            Op::Div | Op::Mod if b.is_zero() => Err(EvalError::DivisionByZero),
+            Op::Div => Ok(a / b),
+            Op::Mod => Ok(a % b),
+            Op::Pow => {
+                let e = b.to_u32().ok_or_else(|| EvalError::BadExponent(b.to_string()))?;
+                Ok(a.pow(e))
+            }
+            _ => unreachable!("{:?} is handled by Op::apply", op)
+        }
+    }
+}
+
+// Exact fractions. Division is true division, and powers may be negative.
+impl Number for BigRational {
+    fn from_i64(n: i64) -> Self {
+        BigRational::from_integer(BigInt::from(n))
+    }
+
+    fn from_decimal(d: Decimal) -> Result<Self, EvalError> {
+        Ok(BigRational::new(BigInt::from(d.digits), BigInt::from(10).pow(d.scale)))
+    }
+
+    fn negate(self) -> Result<Self, EvalError> {
+        Ok(-self)
+    }
+
+    fn apply(op: Op, a: Self, b: Self) -> Result<Self, EvalError> {
+        match op {
+            Op::Add => Ok(a + b),
+            Op::Sub => Ok(a - b),
+            Op::Mul => Ok(a * b),
+            Op::Div | Op::Mod if b.is_zero() => Err(EvalError::DivisionByZero),
+            Op::Div => Ok(a / b),
+            Op::Mod => Ok(a % b),
+            Op::Pow => {
+                let e = if b.is_integer() { b.to_integer().to_i32() } else { None };
+                let e = e.ok_or_else(|| EvalError::BadExponent(b.to_string()))?;
+                if e < 0 && a.is_zero() {
+                    return Err(EvalError::DivisionByZero);
+                }
+                Ok(a.pow(e))
+            }
+            _ => unreachable!("{:?} is handled by Op::apply", op)
+        }
+    }
+}
+
+impl Number for f64 {
+    fn from_i64(n: i64) -> Self {
+        n as f64
+    }
+
+    // Going through the text rounds correctly, which dividing wouldn't
+    fn from_decimal(d: Decimal) -> Result<Self, EvalError> {
+        Ok(d.to_string().parse().expect("Decimal prints as a float"))
+    }
+
+    fn negate(self) -> Result<Self, EvalError> {
+        Ok(-self)
+    }
+
+    fn apply(op: Op, a: Self, b: Self) -> Result<Self, EvalError> {
+        match op {
+            Op::Add => Ok(a + b),
+            Op::Sub => Ok(a - b),
+            Op::Mul => Ok(a * b),
+            Op::Div | Op::Mod if b == 0.0 => Err(EvalError::DivisionByZero),
+            Op::Div => Ok(a / b),
+            Op::Mod => Ok(a % b),
+            Op::Pow => Ok(a.powf(b)),
+            _ => unreachable!("{:?} is handled by Op::apply", op)
+        }
+    }
+}
+
+// The backends selectable from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    I64,
+    I128,
+    Big,
+    Rational,
+    F64,
+    // Integers modulo the given number
+    Modular(u64)
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "i64" => Ok(Backend::I64),
+            "i128" => Ok(Backend::I128),
+            "big" => Ok(Backend::Big),
+            "rational" => Ok(Backend::Rational),
+            "f64" => Ok(Backend::F64),
+            _ if s.starts_with("mod:") => crate::modular::parse_modulus(&s[4..]).map(Backend::Modular),
+            _ => Err(format!("Unknown number backend '{}', expected i64, i128, big, rational, f64 or mod:<m>", s))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_num;
+    use crate::prec::PrecTable;
+
+    #[test]
+    fn test_checked_overflow() {
+        let p1 = PrecTable::left_to_right();
+        let big = "9999999999 * 9999999999";
+        assert_eq!(eval_num::<i64>(big, &p1).unwrap_err().to_string(), "arithmetic overflow");
+        assert_eq!(eval_num::<i128>(big, &p1), Ok(99999999980000000001));
+        assert_eq!(eval_num::<i64>("-(0 - 9223372036854775807 - 1)", &p1).unwrap_err().to_string(),
+            "arithmetic overflow");
+    }
+
+    #[test]
+    fn test_big_backends() {
+        let p2 = PrecTable::addition_first();
+        let e = "9999999999 * 9999999999 * 9999999999 + 1";
+        assert_eq!(eval_num::<BigInt>(e, &p2).unwrap().to_string(), "999999999800000000010000000000");
+        assert_eq!(eval_num::<BigInt>("2 ^ 100", &p2).unwrap().to_string(), "1267650600228229401496703205376");
+    }
+
+    #[test]
+    fn test_rational() {
+        let std = PrecTable::standard();
+        let eval = |e| eval_num::<BigRational>(e, &std).map(|n| n.to_string());
+        assert_eq!(eval("1 / 3 + 1 / 6"), Ok("1/2".to_string()));
+        assert_eq!(eval("(2 / 3) ^ -2"), Ok("9/4".to_string()));
+        assert_eq!(eval("7 / 2 % 1"), Ok("1/2".to_string()));
+        assert!(eval("0 ^ -1").is_err());
+        assert!(eval("4 ^ (1 / 2)").is_err());
+        assert_eq!(eval("0.1 + 0.2"), Ok("3/10".to_string()));
+        assert_eq!(eval("1.50 * 0x10"), Ok("24".to_string()));
+        assert_eq!(eval("4 ^ 0.5").unwrap_err().to_string(), "exponent 1/2 out of range");
+    }
+
+    #[test]
+    fn test_f64() {
+        let std = PrecTable::standard();
+        assert_eq!(eval_num::<f64>("7 / 2", &std), Ok(3.5));
+        assert_eq!(eval_num::<f64>("4 ^ (0 - 1)", &std), Ok(0.25));
+        assert!(eval_num::<f64>("1 / (2 - 2)", &std).is_err());
+        assert_eq!(eval_num::<f64>("0.1 + 0.2", &std), Ok(0.1 + 0.2));
+        assert_eq!(eval_num::<f64>("4 ^ 0.5 * 1_000.25", &std), Ok(2000.5));
+    }
+
+    #[test]
+    fn test_decimals() {
+        let std = PrecTable::standard();
+        let d = |digits, scale| Decimal { digits, scale };
+        assert_eq!(d(125, 2).to_string(), "1.25");
+        assert_eq!(d(5, 3).to_string(), "0.005");
+        assert_eq!(d(100, 1).to_string(), "10.0");
+
+        // Whole number types refuse fractions rather than rounding them
+        assert_eq!(eval_num::<i64>("1 + 2.5", &std).unwrap_err().to_string(),
+            "2.5 needs a number type that can hold fractions");
+        assert!(eval_num::<BigInt>("0.0", &std).is_err());
+        assert_eq!(eval_num::<i64>("0xff + 0b1 + 0o7 + 1_000", &std), Ok(1263));
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let p2 = PrecTable::addition_first();
+        for e in include_str!("../input.txt").lines().filter(|l| !l.trim().is_empty()) {
+            let n = eval_num::<i64>(e, &p2).unwrap();
+            assert_eq!(eval_num::<i128>(e, &p2), Ok(n as i128));
+            assert_eq!(eval_num::<BigInt>(e, &p2), Ok(BigInt::from(n)));
+            assert_eq!(eval_num::<BigRational>(e, &p2), Ok(BigRational::from_i64(n)));
+        }
+    }
+}
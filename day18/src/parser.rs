@@ -0,0 +1,472 @@
+use crate::error::{Error, ParseError};
+use crate::func::builtin_arity;
+use crate::lexer::{lex, Lexeme, Span, Tok};
+use crate::num::Decimal;
+use crate::prec::{Op, PrecTable};
+
+// Flat list of the input's tokens with the brackets checked. Precedence is not
+// decided here, that happens when the list is folded under a particular
+// PrecTable. Nothing is nested, so deep bracketing can't overflow the stack.
+#[derive(Debug)]
+pub enum Token {
+    Number(i64, Span),
+    Decimal(Decimal, Span),
+    Var(String, Span),
+    Op(Op, Span),
+    Neg(Span),
+    Not(Span),
+    Question(Span),
+    Colon(Span),
+    // Name and argument count, always followed by the bracketed arguments
+    Call(String, usize, Span),
+    Comma(Span),
+    // Spans the whole group, up to and including the matching ')'
+    LParen(Span),
+    RParen(Span)
+}
+
+impl Token {
+    pub fn span(&self) -> Span {
+        match self {
+            Token::Number(_, s) | Token::Decimal(_, s) | Token::Var(_, s) | Token::Op(_, s) | Token::Neg(s) | Token::Not(s)
+                | Token::Question(s) | Token::Colon(s) | Token::Call(_, _, s) | Token::Comma(s)
+                | Token::LParen(s) | Token::RParen(s) => *s
+        }
+    }
+
+    // End of the token's own text, which for '(' is not the end of its group
+    fn end(&self) -> usize {
+        match self {
+            Token::LParen(s) => s.start + 1,
+            t => t.span().end
+        }
+    }
+}
+
+fn is_call(out: &[Token], open: usize) -> bool {
+    open > 0 && matches!(out[open - 1], Token::Call(..))
+}
+
+fn expr_tokens(lexemes: &[Lexeme], whole: Span) -> Result<Vec<Token>, ParseError> {
+    let mut out: Vec<Token> = Vec::with_capacity(lexemes.len());
+    // Positions in `out` of the '(' still waiting for a ')', with the number
+    // of commas seen directly inside each
+    let mut open: Vec<(usize, usize)> = Vec::new();
+    // Each '?' still waiting for its ':', with how many brackets it is inside
+    let mut questions: Vec<(usize, Span)> = Vec::new();
+    // A '?' must be answered before its brackets or call argument end
+    let unanswered = |questions: &[(usize, Span)], depth: usize| match questions.last() {
+        Some(&(d, span)) if d == depth => Err(ParseError::MissingElse(span)),
+        _ => Ok(())
+    };
+
+    for (l, lexeme) in lexemes.iter().enumerate() {
+        let span = lexeme.span;
+        let token = match &lexeme.tok {
+            Tok::LParen => {
+                open.push((out.len(), 0));
+                Token::LParen(span)
+            }
+            Tok::RParen => {
+                unanswered(&questions, open.len())?;
+                let (i, commas) = open.pop().ok_or(ParseError::UnmatchedParen(span))?;
+                let group = out[i].span().to(span);
+                let empty = i == out.len() - 1;
+                if is_call(&out, i) {
+                    let arity = if empty { 0 } else { commas + 1 };
+                    if let Token::Call(name, n, call_span) = &mut out[i - 1] {
+                        match builtin_arity(name) {
+                            Some(want) if want != arity => {
+                                return Err(ParseError::WrongArity(want, arity, call_span.to(span)));
+                            }
+                            _ => *n = arity
+                        }
+                    }
+                } else if empty {
+                    return Err(ParseError::EmptyExpr(group));
+                }
+                out[i] = Token::LParen(group);
+                Token::RParen(span)
+            }
+            Tok::Comma => {
+                unanswered(&questions, open.len())?;
+                match open.last_mut() {
+                    Some((i, commas)) if is_call(&out, *i) => {
+                        *commas += 1;
+                        Token::Comma(span)
+                    }
+                    _ => return Err(ParseError::UnexpectedToken(span))
+                }
+            }
+            Tok::Question => {
+                questions.push((open.len(), span));
+                Token::Question(span)
+            }
+            Tok::Colon => match questions.last() {
+                Some(&(d, _)) if d == open.len() => {
+                    questions.pop();
+                    Token::Colon(span)
+                }
+                _ => return Err(ParseError::UnexpectedToken(span))
+            },
+            Tok::Not => Token::Not(span),
+            // Minus is unary at the start of an expression or after an operator
+            Tok::Op(Op::Sub) if matches!(out.last(), None | Some(Token::Op(..)) | Some(Token::Neg(_))
+                | Some(Token::Not(_)) | Some(Token::Question(_)) | Some(Token::Colon(_))
+                | Some(Token::LParen(_)) | Some(Token::Comma(_))) => {
+                Token::Neg(span)
+            }
+            Tok::Op(op) => Token::Op(*op, span),
+            Tok::Number(n) => Token::Number(*n, span),
+            Tok::Decimal(d) => Token::Decimal(*d, span),
+            // A name straight before '(' is a function call
+            Tok::Ident(name) if matches!(lexemes.get(l + 1), Some(Lexeme { tok: Tok::LParen, .. })) => {
+                Token::Call(name.clone(), 0, span)
+            }
+            Tok::Ident(name) => Token::Var(name.clone(), span),
+            Tok::Let | Tok::Assign => return Err(ParseError::UnexpectedToken(span))
+        };
+        out.push(token);
+    }
+
+    // Report the innermost unclosed bracket
+    if let Some(&(i, _)) = open.last() {
+        return Err(ParseError::UnclosedParen(out[i].span()));
+    }
+    unanswered(&questions, 0)?;
+    if out.is_empty() {
+        return Err(ParseError::EmptyExpr(whole));
+    }
+    Ok(out)
+}
+
+pub fn parse(expr: &str) -> Result<Vec<Token>, ParseError> {
+    expr_tokens(&lex(expr)?, Span::new(0, expr.len()))
+}
+
+// A line of input: either a binding or a bare expression
+#[derive(Debug)]
+pub enum Stmt {
+    Let(String, Span, Vec<Token>),
+    Expr(Vec<Token>)
+}
+
+pub fn parse_stmt(line: &str) -> Result<Stmt, ParseError> {
+    let lexemes = lex(line)?;
+    let end = Span::new(line.len(), line.len());
+
+    match lexemes.first() {
+        Some(Lexeme { tok: Tok::Let, span }) => {
+            let (name, name_span) = match lexemes.get(1) {
+                Some(Lexeme { tok: Tok::Ident(name), span }) => (name.clone(), *span),
+                Some(l) => return Err(ParseError::ExpectedIdent(l.span)),
+                None => return Err(ParseError::ExpectedIdent(end))
+            };
+            match lexemes.get(2) {
+                Some(Lexeme { tok: Tok::Assign, .. }) => (),
+                Some(l) => return Err(ParseError::ExpectedAssign(l.span)),
+                None => return Err(ParseError::ExpectedAssign(end))
+            }
+            let rest = Span::new(lexemes[2].span.end, line.len());
+            let tokens = expr_tokens(&lexemes[3..], rest)?;
+            Ok(Stmt::Let(name, span.to(name_span), tokens))
+        }
+        _ => Ok(Stmt::Expr(expr_tokens(&lexemes, Span::new(0, line.len()))?))
+    }
+}
+
+// Operands that are only needed depending on an earlier value: the right
+// side of && and ||, and each branch of ?:
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Guard {
+    And,
+    Or,
+    Then,
+    Else
+}
+
+// Receives the pieces of an expression as the precedence table groups them,
+// in postfix order. Evaluating and building an AST are both folds.
+pub trait Fold {
+    type Out;
+
+    fn num(&self, n: i64, span: Span) -> Result<Self::Out, Error>;
+    fn decimal(&self, d: Decimal, span: Span) -> Result<Self::Out, Error>;
+    fn var(&self, name: &str, span: Span) -> Result<Self::Out, Error>;
+    fn neg(&self, x: Self::Out, span: Span) -> Result<Self::Out, Error>;
+    fn not(&self, x: Self::Out, span: Span) -> Result<Self::Out, Error>;
+    fn bin(&self, op: Op, a: Self::Out, b: Self::Out, span: Span) -> Result<Self::Out, Error>;
+    fn cond(&self, c: Self::Out, a: Self::Out, b: Self::Out, span: Span) -> Result<Self::Out, Error>;
+    fn call(&self, name: &str, args: Vec<Self::Out>, span: Span) -> Result<Self::Out, Error>;
+
+    // Everything folded between guard and the matching unguard is an operand
+    // that `test` may make unnecessary, so it can be skipped. Guards nest.
+    fn guard(&self, _kind: Guard, _test: &Self::Out) {}
+    fn unguard(&self) {}
+}
+
+// Trees nested deeper than this are refused. Folding them is fine, but
+// walking or even dropping one recurses once per level.
+pub const MAX_DEPTH: usize = 500;
+
+// Wraps a fold that builds a tree, pairing each piece with its depth so a
+// piece that would go past MAX_DEPTH fails at its operator instead
+pub struct DepthLimit<F>(pub F);
+
+fn deeper(depth: usize, span: Span) -> Result<usize, Error> {
+    if depth >= MAX_DEPTH {
+        return Err(ParseError::TooDeep(span).into());
+    }
+    Ok(depth + 1)
+}
+
+impl<F: Fold> Fold for DepthLimit<F> {
+    type Out = (F::Out, usize);
+
+    fn num(&self, n: i64, span: Span) -> Result<Self::Out, Error> {
+        Ok((self.0.num(n, span)?, 1))
+    }
+
+    fn decimal(&self, d: Decimal, span: Span) -> Result<Self::Out, Error> {
+        Ok((self.0.decimal(d, span)?, 1))
+    }
+
+    fn var(&self, name: &str, span: Span) -> Result<Self::Out, Error> {
+        Ok((self.0.var(name, span)?, 1))
+    }
+
+    fn neg(&self, (x, depth): Self::Out, span: Span) -> Result<Self::Out, Error> {
+        let depth = deeper(depth, span)?;
+        Ok((self.0.neg(x, span)?, depth))
+    }
+
+    fn not(&self, (x, depth): Self::Out, span: Span) -> Result<Self::Out, Error> {
+        let depth = deeper(depth, span)?;
+        Ok((self.0.not(x, span)?, depth))
+    }
+
+    fn bin(&self, op: Op, (a, da): Self::Out, (b, db): Self::Out, span: Span) -> Result<Self::Out, Error> {
+        let depth = deeper(da.max(db), span)?;
+        Ok((self.0.bin(op, a, b, span)?, depth))
+    }
+
+    fn cond(&self, (c, dc): Self::Out, (a, da): Self::Out, (b, db): Self::Out, span: Span) -> Result<Self::Out, Error> {
+        let depth = deeper(dc.max(da).max(db), span)?;
+        Ok((self.0.cond(c, a, b, span)?, depth))
+    }
+
+    fn call(&self, name: &str, args: Vec<Self::Out>, span: Span) -> Result<Self::Out, Error> {
+        let depth = deeper(args.iter().map(|a| a.1).max().unwrap_or(0), span)?;
+        Ok((self.0.call(name, args.into_iter().map(|a| a.0).collect(), span)?, depth))
+    }
+
+    fn guard(&self, kind: Guard, test: &Self::Out) {
+        self.0.guard(kind, &test.0)
+    }
+
+    fn unguard(&self) {
+        self.0.unguard()
+    }
+}
+
+// An operator waiting for its operands on the shunting-yard stack
+enum Pending<'t> {
+    Bin(Op, Span),
+    Neg(Span),
+    Not(Span),
+    Call(&'t str, usize, Span),
+    Open,
+    // A '?' waiting for its ':', then for its else branch
+    Then(Span),
+    Else(Span)
+}
+
+// Pop the top operator and fold it into the values on top of `values`
+fn reduce<F: Fold>(fold: &F, ops: &mut Vec<Pending<'_>>, values: &mut Vec<F::Out>) -> Result<(), Error> {
+    let out = match ops.pop() {
+        Some(Pending::Bin(op, span)) => {
+            let b = values.pop().expect("Missing right operand");
+            let a = values.pop().expect("Missing left operand");
+            if op.is_logic() {
+                fold.unguard();
+            }
+            fold.bin(op, a, b, span)?
+        }
+        Some(Pending::Neg(span)) => {
+            let x = values.pop().expect("Missing operand");
+            fold.neg(x, span)?
+        }
+        Some(Pending::Not(span)) => {
+            let x = values.pop().expect("Missing operand");
+            fold.not(x, span)?
+        }
+        Some(Pending::Else(span)) => {
+            let b = values.pop().expect("Missing else branch");
+            let a = values.pop().expect("Missing then branch");
+            let c = values.pop().expect("Missing condition");
+            fold.unguard();
+            fold.cond(c, a, b, span)?
+        }
+        _ => unreachable!("Reduced past an open bracket")
+    };
+    values.push(out);
+    Ok(())
+}
+
+// Pop the call whose ')' is at `close` and fold in its arguments, which are
+// the last values pushed
+fn close_call<F: Fold>(fold: &F, ops: &mut Vec<Pending<'_>>, values: &mut Vec<F::Out>, close: Span) -> Result<F::Out, Error> {
+    match ops.pop() {
+        Some(Pending::Call(name, arity, span)) => {
+            let args = values.split_off(values.len() - arity);
+            fold.call(name, args, span.to(close))
+        }
+        _ => unreachable!("Closed a call that wasn't open")
+    }
+}
+
+// Whether the operator on top of the stack binds before `prec`
+fn binds_first(top: Option<&Pending<'_>>, table: &PrecTable, prec: i32) -> bool {
+    match top {
+        Some(Pending::Bin(op, _)) => table.binds_first(Some(*op), prec),
+        Some(Pending::Neg(_)) | Some(Pending::Not(_)) => table.binds_first(None, prec),
+        // ?: binds loosest and is right associative, so nothing reduces it
+        Some(Pending::Call(..)) | Some(Pending::Open) | Some(Pending::Then(_)) | Some(Pending::Else(_)) | None => false
+    }
+}
+
+// Reduce until `stop` is on top of the stack
+fn reduce_until<F, S>(fold: &F, ops: &mut Vec<Pending<'_>>, values: &mut Vec<F::Out>, stop: S) -> Result<(), Error>
+    where F: Fold, S: Fn(&Pending<'_>) -> bool
+{
+    while !ops.last().map(&stop).unwrap_or(true) {
+        reduce(fold, ops, values)?;
+    }
+    Ok(())
+}
+
+// Shunting-yard over the flat tokens with explicit operator and value stacks,
+// so neither nesting depth nor chain length uses the call stack.
+pub fn fold_expr<F: Fold>(tokens: &[Token], table: &PrecTable, fold: &F) -> Result<F::Out, Error> {
+    let mut ops: Vec<Pending> = Vec::new();
+    let mut values: Vec<F::Out> = Vec::new();
+    let mut want_term = true;
+    // Where a missing term would have been, for error reporting
+    let mut end = 0;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let after_open = i > 0 && matches!(tokens[i - 1], Token::LParen(_));
+        match (want_term, token) {
+            (true, Token::Number(n, span)) => {
+                values.push(fold.num(*n, *span)?);
+                want_term = false;
+            }
+            (true, Token::Decimal(d, span)) => {
+                values.push(fold.decimal(*d, *span)?);
+                want_term = false;
+            }
+            (true, Token::Var(name, span)) => {
+                values.push(fold.var(name, *span)?);
+                want_term = false;
+            }
+            (true, Token::Neg(span)) => ops.push(Pending::Neg(*span)),
+            (true, Token::Not(span)) => ops.push(Pending::Not(*span)),
+            (true, Token::Call(name, arity, span)) => ops.push(Pending::Call(name, *arity, *span)),
+            (true, Token::LParen(_)) => ops.push(Pending::Open),
+            (true, Token::Op(_, span)) | (true, Token::Question(span)) | (true, Token::Colon(span)) => {
+                return Err(ParseError::ExpectedTerm(*span).into());
+            }
+            // Only a call's brackets can be empty
+            (true, Token::RParen(span)) if after_open => {
+                ops.pop();
+                let value = close_call(fold, &mut ops, &mut values, *span)?;
+                values.push(value);
+                want_term = false;
+            }
+            (true, Token::RParen(_)) | (true, Token::Comma(_)) => {
+                return Err(ParseError::ExpectedTerm(Span::new(end, end)).into());
+            }
+            (false, Token::Op(op, span)) => {
+                let prec = table.get(*op).0;
+                while binds_first(ops.last(), table, prec) {
+                    reduce(fold, &mut ops, &mut values)?;
+                }
+                // The left operand is complete, so && and || can tell
+                // whether they need the right
+                match op {
+                    Op::And => fold.guard(Guard::And, values.last().expect("Missing left operand")),
+                    Op::Or => fold.guard(Guard::Or, values.last().expect("Missing left operand")),
+                    _ => ()
+                }
+                ops.push(Pending::Bin(*op, *span));
+                want_term = true;
+            }
+            (false, Token::Question(span)) => {
+                reduce_until(fold, &mut ops, &mut values, |p| {
+                    matches!(p, Pending::Open | Pending::Call(..) | Pending::Then(_) | Pending::Else(_))
+                })?;
+                fold.guard(Guard::Then, values.last().expect("Missing condition"));
+                ops.push(Pending::Then(*span));
+                want_term = true;
+            }
+            (false, Token::Colon(_)) => {
+                reduce_until(fold, &mut ops, &mut values, |p| matches!(p, Pending::Then(_)))?;
+                let span = match ops.pop() {
+                    Some(Pending::Then(span)) => span,
+                    _ => unreachable!("':' without '?'")
+                };
+                fold.unguard();
+                fold.guard(Guard::Else, &values[values.len() - 2]);
+                ops.push(Pending::Else(span));
+                want_term = true;
+            }
+            (false, Token::Comma(_)) => {
+                reduce_until(fold, &mut ops, &mut values, |p| matches!(p, Pending::Open))?;
+                want_term = true;
+            }
+            (false, Token::RParen(span)) => {
+                reduce_until(fold, &mut ops, &mut values, |p| matches!(p, Pending::Open))?;
+                ops.pop();
+                if matches!(ops.last(), Some(Pending::Call(..))) {
+                    let value = close_call(fold, &mut ops, &mut values, *span)?;
+                    values.push(value);
+                }
+            }
+            (false, t) => return Err(ParseError::ExpectedOperator(t.span()).into())
+        }
+        end = token.end();
+    }
+
+    if want_term {
+        return Err(ParseError::ExpectedTerm(Span::new(end, end)).into());
+    }
+    while !ops.is_empty() {
+        reduce(fold, &mut ops, &mut values)?;
+    }
+    Ok(values.pop().expect("Must fold at least 1 token"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stmt() {
+        match parse_stmt("let x = 2 * y") {
+            Ok(Stmt::Let(name, span, tokens)) => {
+                assert_eq!(name, "x");
+                assert_eq!(span, Span::new(0, 5));
+                assert_eq!(tokens.len(), 3);
+            }
+            other => panic!("Expected let, got {:?}", other)
+        }
+        assert!(matches!(parse_stmt("x * 2"), Ok(Stmt::Expr(_))));
+
+        assert_eq!(parse_stmt("let 2 = 3").unwrap_err(), ParseError::ExpectedIdent(Span::new(4, 5)));
+        assert_eq!(parse_stmt("let").unwrap_err(), ParseError::ExpectedIdent(Span::new(3, 3)));
+        assert_eq!(parse_stmt("let x 3").unwrap_err(), ParseError::ExpectedAssign(Span::new(6, 7)));
+        assert_eq!(parse_stmt("let x =").unwrap_err(), ParseError::EmptyExpr(Span::new(7, 7)));
+        assert_eq!(parse_stmt("x = 3").unwrap_err(), ParseError::UnexpectedToken(Span::new(2, 3)));
+        assert_eq!(parse_stmt("1 + let").unwrap_err(), ParseError::UnexpectedToken(Span::new(4, 7)));
+    }
+}
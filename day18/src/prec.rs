@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::error::EvalError;
+use crate::num::{truthy, Number};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Eq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or
+}
+
+impl Op {
+    // Comparisons and logic give 1 for true and 0 for false, whatever the
+    // number type. Arithmetic is left to the type.
+    pub fn apply<N: Number>(self, a: N, b: N) -> Result<N, EvalError> {
+        let truth = |t: bool| Ok(N::from_i64(t as i64));
+        match self {
+            Op::Eq => truth(a == b),
+            Op::Lt => truth(a < b),
+            Op::Gt => truth(a > b),
+            Op::Le => truth(a <= b),
+            Op::Ge => truth(a >= b),
+            Op::And => truth(truthy(&a) && truthy(&b)),
+            Op::Or => truth(truthy(&a) || truthy(&b)),
+            _ => N::apply(self, a, b)
+        }
+    }
+
+    pub fn is_logic(self) -> bool {
+        matches!(self, Op::And | Op::Or)
+    }
+
+    pub fn from_symbol(s: &str) -> Option<Op> {
+        match s {
+            "+" => Some(Op::Add),
+            "-" => Some(Op::Sub),
+            "*" => Some(Op::Mul),
+            "/" => Some(Op::Div),
+            "%" => Some(Op::Mod),
+            "^" => Some(Op::Pow),
+            "==" => Some(Op::Eq),
+            "<" => Some(Op::Lt),
+            ">" => Some(Op::Gt),
+            "<=" => Some(Op::Le),
+            ">=" => Some(Op::Ge),
+            "&&" => Some(Op::And),
+            "||" => Some(Op::Or),
+            _ => None
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Mod => "%",
+            Op::Pow => "^",
+            Op::Eq => "==",
+            Op::Lt => "<",
+            Op::Gt => ">",
+            Op::Le => "<=",
+            Op::Ge => ">=",
+            Op::And => "&&",
+            Op::Or => "||"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right
+}
+
+// Operator precedence and associativity. Higher precedence binds tighter.
+// Unary minus and `!` bind their operand at the `neg` precedence, so any
+// binary operator above it (e.g. ^ normally) is applied first. The ternary
+// `?:` always binds loosest of all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrecTable {
+    ops: HashMap<Op, (i32, Assoc)>,
+    pub neg: i32
+}
+
+impl PrecTable {
+    pub fn new(ops: &[(Op, i32, Assoc)], neg: i32) -> Self {
+        // Comparisons and logic sit below the arithmetic in every table
+        let logic = [
+            (Op::Eq, -1, Assoc::Left),
+            (Op::Lt, -1, Assoc::Left),
+            (Op::Gt, -1, Assoc::Left),
+            (Op::Le, -1, Assoc::Left),
+            (Op::Ge, -1, Assoc::Left),
+            (Op::And, -2, Assoc::Left),
+            (Op::Or, -3, Assoc::Left)
+        ];
+        Self {
+            ops: logic.iter().chain(ops.iter()).map(|&(op, p, a)| (op, (p, a))).collect(),
+            neg
+        }
+    }
+
+    // Part 1: everything evaluated strictly left to right
+    pub fn left_to_right() -> Self {
+        Self::new(&[
+            (Op::Add, 1, Assoc::Left),
+            (Op::Sub, 1, Assoc::Left),
+            (Op::Mul, 1, Assoc::Left),
+            (Op::Div, 1, Assoc::Left),
+            (Op::Mod, 1, Assoc::Left),
+            (Op::Pow, 1, Assoc::Left)
+        ], 2)
+    }
+
+    // Part 2: additions are reduced before multiplications
+    pub fn addition_first() -> Self {
+        Self::new(&[
+            (Op::Add, 2, Assoc::Left),
+            (Op::Sub, 2, Assoc::Left),
+            (Op::Mul, 1, Assoc::Left),
+            (Op::Div, 1, Assoc::Left),
+            (Op::Mod, 1, Assoc::Left),
+            (Op::Pow, 4, Assoc::Right)
+        ], 3)
+    }
+
+    // The usual maths ordering
+    pub fn standard() -> Self {
+        Self::new(&[
+            (Op::Add, 1, Assoc::Left),
+            (Op::Sub, 1, Assoc::Left),
+            (Op::Mul, 2, Assoc::Left),
+            (Op::Div, 2, Assoc::Left),
+            (Op::Mod, 2, Assoc::Left),
+            (Op::Pow, 4, Assoc::Right)
+        ], 3)
+    }
+
+    pub fn set(&mut self, op: Op, prec: i32, assoc: Assoc) {
+        self.ops.insert(op, (prec, assoc));
+    }
+
+    pub fn get(&self, op: Op) -> (i32, Assoc) {
+        *self.ops.get(&op).expect("Operator missing from precedence table")
+    }
+
+    // Whether an operator still waiting for its right operand binds before a
+    // binary operator of precedence `prec` that follows. This matches
+    // precedence climbing: an equal level goes first when it is left
+    // associative. `waiting` is None for a unary minus or `!`, which takes
+    // everything binding at least as tightly as it does.
+    pub fn binds_first(&self, waiting: Option<Op>, prec: i32) -> bool {
+        match waiting {
+            Some(op) => {
+                let (top, assoc) = self.get(op);
+                top > prec || (top == prec && assoc == Assoc::Left)
+            }
+            None => self.neg > prec
+        }
+    }
+}
+
+// Parse a table spec like "+:2,*:1:left,neg:3". Each entry is op:precedence
+// with an optional associativity (l/left or r/right, default left); "neg" sets
+// the unary minus precedence. An entry can also name a whole preset ("p1",
+// "p2" or "standard") to start from. Anything not mentioned keeps the part 1
+// default.
+impl FromStr for PrecTable {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut table = PrecTable::left_to_right();
+
+        for entry in spec.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            match entry {
+                "p1" => { table = PrecTable::left_to_right(); continue; }
+                "p2" => { table = PrecTable::addition_first(); continue; }
+                "standard" => { table = PrecTable::standard(); continue; }
+                _ => ()
+            }
+
+            let parts: Vec<&str> = entry.split(':').map(|s| s.trim()).collect();
+            if parts.len() < 2 || parts.len() > 3 {
+                return Err(format!("Bad precedence entry '{}', expected op:prec[:assoc]", entry));
+            }
+
+            let prec = parts[1].parse::<i32>()
+                .map_err(|_| format!("Bad precedence '{}' for '{}'", parts[1], parts[0]))?;
+            if parts[0] == "neg" && parts.len() == 2 {
+                table.neg = prec;
+                continue;
+            }
+
+            let op = Op::from_symbol(parts[0])
+                .ok_or_else(|| format!("Unknown operator '{}'", parts[0]))?;
+            let assoc = match parts.get(2) {
+                None | Some(&"l") | Some(&"left") => Assoc::Left,
+                Some(&"r") | Some(&"right") => Assoc::Right,
+                Some(a) => return Err(format!("Unknown associativity '{}'", a))
+            };
+
+            table.set(op, prec, assoc);
+        }
+
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prec_table_spec() {
+        assert_eq!("+:2,-:2,^:4:r,neg:3".parse::<PrecTable>(), Ok(PrecTable::addition_first()));
+        assert_eq!("p2".parse::<PrecTable>(), Ok(PrecTable::addition_first()));
+        assert_eq!("".parse::<PrecTable>(), Ok(PrecTable::left_to_right()));
+        assert_eq!("standard".parse::<PrecTable>(), Ok(PrecTable::standard()));
+        assert!("?:1".parse::<PrecTable>().is_err());
+        assert!("+:x".parse::<PrecTable>().is_err());
+        assert!("+:1:up".parse::<PrecTable>().is_err());
+
+        let table: PrecTable = "standard,==:-4,&&:-1:r".parse().unwrap();
+        assert_eq!(table.get(Op::Eq), (-4, Assoc::Left));
+        assert_eq!(table.get(Op::And), (-1, Assoc::Right));
+        assert_eq!(table.get(Op::Or), (-3, Assoc::Left));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::ast::Expr;
+use crate::env::Env;
+use crate::error::Error;
+use crate::exec_line;
+use crate::num::Number;
+use crate::parser::parse;
+use crate::prec::PrecTable;
+use crate::rpn::to_rpn;
+use crate::trace::trace;
+
+const HELP: &str = "\
+expr                evaluate under every active mode
+let x = expr        bind a variable, separately in each mode
+:mode [spec ...]    show the active modes, or replace them, e.g. :mode p2 standard *:2,+:1
+:ast expr           show the grouping
+:rpn expr           show as RPN
+:trace expr         show each step of evaluation
+:history            list the lines entered so far
+:help               show this";
+
+// A precedence mode with its own variables, named by the spec it came from
+struct Mode<N> {
+    name: String,
+    table: PrecTable,
+    env: Env<N>
+}
+
+impl<N: Number> Mode<N> {
+    fn new(name: &str) -> Result<Self, String> {
+        Ok(Mode { name: name.to_string(), table: name.parse()?, env: Env::new() })
+    }
+}
+
+// Line at a time calculator. Each line gives back the text to show, so it
+// can be driven from stdin or from tests alike.
+pub struct Repl<N> {
+    modes: Vec<Mode<N>>,
+    history: Vec<String>
+}
+
+impl<N: Number> Default for Repl<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: Number> Repl<N> {
+    // Starts with both puzzle parts active
+    pub fn new() -> Self {
+        let modes = ["p1", "p2"].iter().map(|m| Mode::new(m).expect("Preset spec")).collect();
+        Repl { modes, history: Vec::new() }
+    }
+
+    pub fn line(&mut self, line: &str) -> String {
+        let line = line.trim();
+        if line.is_empty() {
+            return String::new();
+        }
+        self.history.push(line.to_string());
+        let line_no = self.history.len();
+
+        let (command, rest) = match line.find(char::is_whitespace) {
+            Some(i) if line.starts_with(':') => (&line[..i], line[i..].trim()),
+            _ if line.starts_with(':') => (line, ""),
+            _ => ("", line)
+        };
+        match command {
+            "" => self.each_mode(rest, line_no, |mode| {
+                match exec_line(rest, &mode.table, &mut mode.env)? {
+                    Some(n) => Ok(n.to_string()),
+                    None => Ok("ok".to_string())
+                }
+            }),
+            ":ast" => self.each_mode(rest, line_no, |mode| {
+                Ok(Expr::build(&parse(rest)?, &mode.table)?.pretty_full())
+            }),
+            ":rpn" => self.each_mode(rest, line_no, |mode| to_rpn(&parse(rest)?, &mode.table)),
+            ":trace" => self.each_mode(rest, line_no, |mode| {
+                let steps = trace(&parse(rest)?, &mode.table, &mode.env)?;
+                Ok(steps.iter().fold(rest.to_string(), |out, s| format!("{}\n  = {}", out, s)))
+            }),
+            ":mode" => self.set_modes(rest),
+            ":history" => self.history.iter().enumerate()
+                .map(|(i, l)| format!("{:>4}  {}", i + 1, l))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ":help" => HELP.to_string(),
+            _ => format!("unknown command '{}', try :help", command)
+        }
+    }
+
+    // One line of output per mode. Errors point into `src`, numbered by the
+    // line's place in the history.
+    fn each_mode<F>(&mut self, src: &str, line_no: usize, mut f: F) -> String
+        where F: FnMut(&mut Mode<N>) -> Result<String, Error>
+    {
+        let mut out = Vec::new();
+        for mode in self.modes.iter_mut() {
+            match f(mode) {
+                Ok(s) => out.push(format!("{}: {}", mode.name, s)),
+                Err(e) => out.push(format!("{}: {}", mode.name, e.render(src, line_no).trim_end()))
+            }
+        }
+        out.join("\n")
+    }
+
+    // Replace the active modes. Modes kept from before keep their variables.
+    // Every spec is checked before any mode is taken, so a bad one leaves the
+    // active modes as they were.
+    fn set_modes(&mut self, specs: &str) -> String {
+        if !specs.is_empty() {
+            // None for a mode to keep, each active mode being kept at most once
+            let mut kept = Vec::new();
+            let mut plan = Vec::new();
+            for spec in specs.split_whitespace() {
+                if self.modes.iter().any(|m| m.name == spec) && !kept.contains(&spec) {
+                    kept.push(spec);
+                    plan.push((spec, None));
+                } else {
+                    match Mode::new(spec) {
+                        Ok(mode) => plan.push((spec, Some(mode))),
+                        Err(e) => return e
+                    }
+                }
+            }
+            self.modes = plan.into_iter().map(|(spec, mode)| mode.unwrap_or_else(|| {
+                let i = self.modes.iter().position(|m| m.name == spec).expect("Kept mode");
+                self.modes.remove(i)
+            })).collect();
+        }
+        format!("modes: {}", self.modes.iter().map(|m| m.name.as_str()).collect::<Vec<_>>().join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(lines: &[&str]) -> Vec<String> {
+        let mut repl: Repl<i64> = Repl::new();
+        lines.iter().map(|l| repl.line(l)).collect()
+    }
+
+    #[test]
+    fn test_repl_eval() {
+        assert_eq!(run(&["2 * 3 + (4 * 5)", "", "let x = 2 * 3 + 1", "x"]), vec![
+            "p1: 26\np2: 46",
+            "",
+            "p1: ok\np2: ok",
+            "p1: 7\np2: 8"
+        ]);
+        assert_eq!(run(&["1 +"]), vec!["\
+p1: error: expected a number, variable or '('
+ --> line 1, column 4
+  |
+1 | 1 +
+  |    ^
+p2: error: expected a number, variable or '('
+ --> line 1, column 4
+  |
+1 | 1 +
+  |    ^"]);
+    }
+
+    #[test]
+    fn test_repl_commands() {
+        let out = run(&[
+            ":ast 2 * 3 + 1",
+            ":rpn 2 * 3 + 1",
+            ":trace 2 * 3 + 1",
+            ":bogus",
+            ":history"
+        ]);
+        assert_eq!(out[0], "p1: (2 * 3) + 1\np2: 2 * (3 + 1)");
+        assert_eq!(out[1], "p1: 2 3 * 1 +\np2: 2 3 1 + *");
+        assert_eq!(out[2], "p1: 2 * 3 + 1\n  = 6 + 1\n  = 7\np2: 2 * 3 + 1\n  = 2 * 4\n  = 8");
+        assert_eq!(out[3], "unknown command ':bogus', try :help");
+        assert_eq!(out[4].lines().last(), Some("   5  :history"));
+    }
+
+    #[test]
+    fn test_repl_modes() {
+        let out = run(&[
+            ":mode",
+            "let x = 2 + 2 * 2",
+            ":mode p2 standard",
+            "x",
+            "let y = 2 ^ 3 ^ 2",
+            ":mode standard *:0",
+            ":mode +:1:sideways",
+            "y"
+        ]);
+        assert_eq!(out[0], "modes: p1 p2");
+        assert_eq!(out[2], "modes: p2 standard");
+        // Only the mode kept from before still knows x
+        assert_eq!(out[3], "p2: 8\nstandard: error: undefined variable 'x'\n --> line 4, column 1\n  |\n4 | x\n  | ^");
+        assert_eq!(out[5], "modes: standard *:0");
+        assert_eq!(out[6], "Unknown associativity 'sideways'");
+        assert_eq!(out[7].lines().next(), Some("standard: 512"));
+
+        // A bad spec anywhere leaves every mode in place
+        let out = run(&["let x = 5", ":mode p1 +:1:bad", ":mode", "x"]);
+        assert_eq!(out[1], "Unknown associativity 'bad'");
+        assert_eq!(out[2], "modes: p1 p2");
+        assert_eq!(out[3], "p1: 5\np2: 5");
+    }
+}
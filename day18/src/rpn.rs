@@ -0,0 +1,284 @@
+use std::cell::RefCell;
+
+use crate::error::{Error, ParseError};
+use crate::lexer::{lex, Lexeme, Span, Tok};
+use crate::num::Decimal;
+use crate::parser::{fold_expr, parse, Fold, Guard, Token};
+use crate::prec::{Op, PrecTable};
+
+// Unary minus in RPN, since '-' is always binary there
+pub const NEG: &str = "neg";
+
+// Writes each piece out as it arrives, which is already postfix order
+struct RpnWriter {
+    items: RefCell<Vec<String>>
+}
+
+impl RpnWriter {
+    fn push(&self, item: String) -> Result<(), Error> {
+        self.items.borrow_mut().push(item);
+        Ok(())
+    }
+}
+
+impl Fold for RpnWriter {
+    type Out = ();
+
+    fn num(&self, n: i64, _: Span) -> Result<(), Error> {
+        self.push(n.to_string())
+    }
+
+    fn decimal(&self, d: Decimal, _: Span) -> Result<(), Error> {
+        self.push(d.to_string())
+    }
+
+    fn var(&self, name: &str, _: Span) -> Result<(), Error> {
+        self.push(name.to_string())
+    }
+
+    fn neg(&self, _: (), _: Span) -> Result<(), Error> {
+        self.push(NEG.to_string())
+    }
+
+    fn not(&self, _: (), _: Span) -> Result<(), Error> {
+        self.push("!".to_string())
+    }
+
+    fn bin(&self, op: Op, _: (), _: (), _: Span) -> Result<(), Error> {
+        self.push(op.symbol().to_string())
+    }
+
+    fn cond(&self, _: (), _: (), _: (), _: Span) -> Result<(), Error> {
+        self.push("?".to_string())
+    }
+
+    fn call(&self, name: &str, _: Vec<()>, _: Span) -> Result<(), Error> {
+        self.push(name.to_string())
+    }
+}
+
+// Space separated RPN for the expression as grouped by `table`
+pub fn to_rpn(tokens: &[Token], table: &PrecTable) -> Result<String, Error> {
+    let writer = RpnWriter { items: RefCell::new(Vec::new()) };
+    fold_expr(tokens, table, &writer)?;
+    Ok(writer.items.into_inner().join(" "))
+}
+
+pub fn to_rpn_str(expr: &str, table: &PrecTable) -> Result<String, Error> {
+    to_rpn(&parse(expr)?, table)
+}
+
+// How many values a piece of RPN takes, or None if it can't appear in RPN
+fn takes<A: Fn(&str) -> Option<usize>>(tok: &Tok, arity: &A) -> Option<usize> {
+    match tok {
+        Tok::Number(_) | Tok::Decimal(_) => Some(0),
+        Tok::Ident(name) if name == NEG => Some(1),
+        Tok::Ident(name) => Some(arity(name).unwrap_or(0)),
+        Tok::Not => Some(1),
+        Tok::Op(_) => Some(2),
+        Tok::Question => Some(3),
+        _ => None
+    }
+}
+
+// The guard to open before each piece: the right side of && and || and each
+// branch of ?: start there. RPN only says what an operand was for once it
+// has been read, so this is worked out ahead by tracking where each value on
+// the stack began. It stops at the first piece without enough values, which
+// folding reports.
+fn guards<A: Fn(&str) -> Option<usize>>(lexemes: &[Lexeme], arity: &A) -> Vec<Option<Guard>> {
+    let mut guards = vec![None; lexemes.len()];
+    let mut starts: Vec<usize> = Vec::new();
+    for (i, lexeme) in lexemes.iter().enumerate() {
+        let n = match takes(&lexeme.tok, arity) {
+            Some(n) if n <= starts.len() => n,
+            _ => break
+        };
+        let args = starts.split_off(starts.len() - n);
+        match (&lexeme.tok, args.as_slice()) {
+            (Tok::Op(Op::And), [_, b]) => guards[*b] = Some(Guard::And),
+            (Tok::Op(Op::Or), [_, b]) => guards[*b] = Some(Guard::Or),
+            (Tok::Question, [_, a, b]) => {
+                guards[*a] = Some(Guard::Then);
+                guards[*b] = Some(Guard::Else);
+            }
+            _ => ()
+        }
+        starts.push(args.first().copied().unwrap_or(i));
+    }
+    guards
+}
+
+// Read RPN and hand its pieces to `fold`. RPN carries its own grouping so no
+// table is needed. A name is a function call when `arity` knows it, taking
+// that many values, and a variable otherwise. The span of each value is kept
+// for error reporting. A ternary is written "c a b ?". Operands are guarded
+// as fold_expr does, so an evaluating fold still short-circuits.
+pub fn fold_rpn<F, A>(src: &str, arity: A, fold: &F) -> Result<F::Out, Error>
+    where F: Fold, A: Fn(&str) -> Option<usize>
+{
+    let mut values: Vec<(F::Out, Span)> = Vec::new();
+    let lexemes = lex(src)?;
+    let guards = guards(&lexemes, &arity);
+
+    for (lexeme, guard) in lexemes.into_iter().zip(guards) {
+        match guard {
+            Some(Guard::Else) => {
+                fold.unguard();
+                fold.guard(Guard::Else, &values[values.len() - 2].0);
+            }
+            Some(kind) => fold.guard(kind, &values.last().expect("Guard with no test").0),
+            None => ()
+        }
+        let span = lexeme.span;
+        let value = match lexeme.tok {
+            Tok::Number(n) => fold.num(n, span)?,
+            Tok::Decimal(d) => fold.decimal(d, span)?,
+            Tok::Ident(ref name) if name == NEG => {
+                let (x, x_span) = values.pop().ok_or(ParseError::MissingOperand(span))?;
+                values.push((fold.neg(x, span)?, x_span.to(span)));
+                continue;
+            }
+            Tok::Ident(ref name) => match arity(name) {
+                Some(n) => {
+                    if values.len() < n {
+                        return Err(ParseError::MissingOperand(span).into());
+                    }
+                    let args = values.split_off(values.len() - n);
+                    let start = args.first().map(|(_, s)| *s).unwrap_or(span);
+                    let args = args.into_iter().map(|(a, _)| a).collect();
+                    values.push((fold.call(name, args, span)?, start.to(span)));
+                    continue;
+                }
+                None => fold.var(name, span)?
+            },
+            Tok::Not => {
+                let (x, x_span) = values.pop().ok_or(ParseError::MissingOperand(span))?;
+                values.push((fold.not(x, span)?, x_span.to(span)));
+                continue;
+            }
+            Tok::Question => {
+                if values.len() < 3 {
+                    return Err(ParseError::MissingOperand(span).into());
+                }
+                let (b, _) = values.pop().unwrap();
+                let (a, _) = values.pop().unwrap();
+                let (c, c_span) = values.pop().unwrap();
+                fold.unguard();
+                values.push((fold.cond(c, a, b, span)?, c_span.to(span)));
+                continue;
+            }
+            Tok::Op(op) => {
+                if values.len() < 2 {
+                    return Err(ParseError::MissingOperand(span).into());
+                }
+                let (b, _) = values.pop().unwrap();
+                let (a, a_span) = values.pop().unwrap();
+                if op.is_logic() {
+                    fold.unguard();
+                }
+                values.push((fold.bin(op, a, b, span)?, a_span.to(span)));
+                continue;
+            }
+            Tok::LParen | Tok::RParen | Tok::Comma | Tok::Colon | Tok::Let | Tok::Assign => return Err(ParseError::UnexpectedToken(span).into())
+        };
+        values.push((value, span));
+    }
+
+    match values.len() {
+        0 => Err(ParseError::EmptyExpr(Span::new(0, src.len())).into()),
+        1 => Ok(values.pop().unwrap().0),
+        n => Err(ParseError::UnusedOperand(values[n - 2].1).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Expr;
+    use crate::env::Env;
+    use crate::error::EvalError;
+    use crate::{eval_expr, eval_p1, eval_p2, eval_rpn};
+
+    #[test]
+    fn test_to_rpn() {
+        let p1 = PrecTable::left_to_right();
+        let p2 = PrecTable::addition_first();
+        let std = PrecTable::standard();
+
+        assert_eq!(to_rpn_str("2 * 3 + (4 * 5)", &p1), Ok("2 3 * 4 5 * +".to_string()));
+        assert_eq!(to_rpn_str("2 * 3 + (4 * 5)", &p2), Ok("2 3 4 5 * + *".to_string()));
+        assert_eq!(to_rpn_str("2 ^ 3 ^ 2", &std), Ok("2 3 2 ^ ^".to_string()));
+        assert_eq!(to_rpn_str("-x * (y - 1)", &std), Ok("x neg y 1 - *".to_string()));
+        assert!(to_rpn_str("2 * (3", &std).is_err());
+    }
+
+    #[test]
+    fn test_eval_rpn() {
+        let mut env: Env = Env::new();
+        assert_eq!(eval_rpn("2 3 4 5 * + *", &env), Ok(46));
+        assert_eq!(eval_rpn("7 neg 2 ^", &env), Ok(49));
+        assert_eq!(eval_rpn("x 1 +", &env).unwrap_err().to_string(), "undefined variable 'x'");
+        env.set("x", 9);
+        assert_eq!(eval_rpn("x 1 +", &env), Ok(10));
+
+        let err = |e| Err(Error::Parse(e));
+        assert_eq!(eval_rpn("1 +", &env), err(ParseError::MissingOperand(Span::new(2, 3))));
+        assert_eq!(eval_rpn("neg", &env), err(ParseError::MissingOperand(Span::new(0, 3))));
+        assert_eq!(eval_rpn("1 2 3 +", &env), err(ParseError::UnusedOperand(Span::new(0, 1))));
+        assert_eq!(eval_rpn("(1 2 +)", &env), err(ParseError::UnexpectedToken(Span::new(0, 1))));
+        assert_eq!(eval_rpn(" ", &env), err(ParseError::EmptyExpr(Span::new(0, 1))));
+
+        assert_eq!(Expr::from_rpn("1 2 3 * + neg").unwrap().pretty(&PrecTable::standard()), "-(1 + 2 * 3)");
+
+        // Functions take as many values as they have arguments
+        assert_eq!(to_rpn_str("max(1, 2 * 3) + abs(x)", &PrecTable::standard()),
+            Ok("1 2 3 * max x abs +".to_string()));
+        assert_eq!(eval_rpn("1 2 3 * max x abs +", &env), Ok(15));
+        assert_eq!(eval_rpn("1 max", &env), err(ParseError::MissingOperand(Span::new(2, 5))));
+
+        // A ternary takes its condition and both branches
+        assert_eq!(to_rpn_str("!x || x > 3 ? 1 : 2", &PrecTable::standard()),
+            Ok("x ! x 3 > || 1 2 ?".to_string()));
+        assert_eq!(eval_rpn("x ! x 3 > || 1 2 ?", &env), Ok(1));
+        assert_eq!(eval_rpn("0 1 2 ?", &env), Ok(2));
+        assert_eq!(eval_rpn("1 2 ?", &env), err(ParseError::MissingOperand(Span::new(4, 5))));
+        assert_eq!(eval_rpn("1 2 : 3", &env), err(ParseError::UnexpectedToken(Span::new(4, 5))));
+    }
+
+    #[test]
+    fn test_rpn_round_trip() {
+        let p1 = PrecTable::left_to_right();
+        let p2 = PrecTable::addition_first();
+        let env: Env = Env::new();
+        for e in include_str!("../input.txt").lines().filter(|l| !l.trim().is_empty()) {
+            assert_eq!(eval_rpn(&to_rpn_str(e, &p1).unwrap(), &env), eval_p1(e), "{}", e);
+            assert_eq!(eval_rpn(&to_rpn_str(e, &p2).unwrap(), &env), eval_p2(e), "{}", e);
+        }
+
+        // Skipped operands stay skipped, so errors in them don't surface
+        let std = PrecTable::standard();
+        let mut env: Env = Env::new();
+        env.set("x", 0);
+        for e in ["x == 0 ? 0 : 10 / x", "x && 1 / x", "!x || y", "x ? y : 1 ? 2 : 3 / x"].iter() {
+            let rpn = to_rpn_str(e, &std).unwrap();
+            let want = eval_expr(&parse(e).unwrap(), &std, &env);
+            assert!(want.is_ok(), "{}", e);
+            assert_eq!(eval_rpn(&rpn, &env), want, "{} as {}", e, rpn);
+        }
+        assert_eq!(eval_rpn("0 1 0 / &&", &env), Ok(0));
+        assert_eq!(eval_rpn("1 1 0 / &&", &env), Err(Error::Eval(EvalError::DivisionByZero, Span::new(6, 7))));
+    }
+
+    #[test]
+    fn test_long_rpn() {
+        // Read with an explicit stack, so length is no problem
+        let n = 200_000;
+        let env: Env = Env::new();
+        assert_eq!(eval_rpn(&format!("1{}", " 1 +".repeat(n)), &env), Ok(n as i64 + 1));
+        assert_eq!(eval_rpn(&format!("3{}", " neg".repeat(n + 1)), &env), Ok(-3));
+        assert_eq!(eval_rpn(&format!("0{}", " 1 0 / &&".repeat(n)), &env), Ok(0));
+        // Guards nested n deep
+        assert_eq!(eval_rpn(&format!("0{}{}", " 1 0 /".repeat(n), " &&".repeat(n)), &env), Ok(0));
+    }
+}
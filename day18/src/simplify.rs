@@ -0,0 +1,300 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::ast::Expr;
+use crate::env::Env;
+use crate::num::truthy;
+use crate::prec::Op;
+
+// Factors with their powers. A factor is a variable, or any expression that
+// can't be multiplied out such as a division by something unknown.
+type Monomial = BTreeMap<Expr, u32>;
+
+// Sum of monomials with non-zero coefficients. The constant term has the
+// empty monomial. Every operation returns None on overflow, in which case
+// the caller keeps the expression as it was.
+#[derive(Debug, Clone, PartialEq)]
+struct Poly {
+    terms: BTreeMap<Monomial, i64>
+}
+
+impl Poly {
+    fn constant(n: i64) -> Poly {
+        let mut terms = BTreeMap::new();
+        if n != 0 {
+            terms.insert(Monomial::new(), n);
+        }
+        Poly { terms }
+    }
+
+    fn factor(e: Expr) -> Poly {
+        let mut m = Monomial::new();
+        m.insert(e, 1);
+        let mut terms = BTreeMap::new();
+        terms.insert(m, 1);
+        Poly { terms }
+    }
+
+    fn as_constant(&self) -> Option<i64> {
+        match self.terms.iter().next() {
+            None => Some(0),
+            Some((m, c)) if m.is_empty() && self.terms.len() == 1 => Some(*c),
+            _ => None
+        }
+    }
+
+    fn add_term(&mut self, m: Monomial, c: i64) -> Option<()> {
+        let sum = self.terms.get(&m).unwrap_or(&0).checked_add(c)?;
+        if sum == 0 {
+            self.terms.remove(&m);
+        } else {
+            self.terms.insert(m, sum);
+        }
+        Some(())
+    }
+
+    fn add(&self, other: &Poly) -> Option<Poly> {
+        let mut out = self.clone();
+        for (m, c) in &other.terms {
+            out.add_term(m.clone(), *c)?;
+        }
+        Some(out)
+    }
+
+    fn neg(&self) -> Option<Poly> {
+        let mut out = self.clone();
+        for c in out.terms.values_mut() {
+            *c = c.checked_neg()?;
+        }
+        Some(out)
+    }
+
+    fn mul(&self, other: &Poly) -> Option<Poly> {
+        let mut out = Poly::constant(0);
+        for (ma, ca) in &self.terms {
+            for (mb, cb) in &other.terms {
+                let mut m = ma.clone();
+                for (f, p) in mb {
+                    let power = m.entry(f.clone()).or_insert(0);
+                    *power = power.checked_add(*p)?;
+                }
+                out.add_term(m, ca.checked_mul(*cb)?)?;
+            }
+        }
+        Some(out)
+    }
+
+    // Sums are only multiplied out for small powers, so the result stays
+    // readable
+    fn pow(&self, e: u32) -> Option<Poly> {
+        match self.terms.len() {
+            _ if e == 0 => Some(Poly::constant(1)),
+            0 => Some(Poly::constant(0)),
+            1 => {
+                let (m, c) = self.terms.iter().next().unwrap();
+                let mut m = m.clone();
+                for power in m.values_mut() {
+                    *power = power.checked_mul(e)?;
+                }
+                let mut out = Poly::constant(0);
+                out.add_term(m, c.checked_pow(e)?)?;
+                Some(out)
+            }
+            _ if e <= 8 => (1..e).try_fold(self.clone(), |acc, _| acc.mul(self)),
+            _ => None
+        }
+    }
+
+    // Highest degree first, constant last
+    fn to_expr(&self) -> Expr {
+        let mut terms: Vec<(&Monomial, i64)> = self.terms.iter().map(|(m, c)| (m, *c)).collect();
+        terms.sort_by_key(|(m, _)| Reverse(m.values().map(|p| *p as u64).sum::<u64>()));
+
+        let mut out: Option<Expr> = None;
+        for (m, c) in terms {
+            out = Some(match out {
+                None if c == -1 && !m.is_empty() => Expr::Neg(Box::new(term(m, 1))),
+                None => term(m, c),
+                Some(acc) => match c.checked_neg() {
+                    Some(n) if c < 0 => Expr::Bin(Op::Sub, Box::new(acc), Box::new(term(m, n))),
+                    _ => Expr::Bin(Op::Add, Box::new(acc), Box::new(term(m, c)))
+                }
+            });
+        }
+        out.unwrap_or(Expr::Num(0))
+    }
+}
+
+fn term(m: &Monomial, c: i64) -> Expr {
+    let start = if c == 1 && !m.is_empty() { None } else { Some(Expr::Num(c)) };
+    m.iter()
+        .map(|(f, p)| match p {
+            1 => f.clone(),
+            p => Expr::Bin(Op::Pow, Box::new(f.clone()), Box::new(Expr::Num(*p as i64)))
+        })
+        .fold(start, |acc, f| Some(match acc {
+            None => f,
+            Some(acc) => Expr::Bin(Op::Mul, Box::new(acc), Box::new(f))
+        }))
+        .expect("Term with no coefficient or factors")
+}
+
+fn to_poly(expr: &Expr, env: &Env) -> Poly {
+    match expr {
+        Expr::Num(n) => Poly::constant(*n),
+        // Coefficients are whole numbers, so a fraction stays as it is
+        Expr::Decimal(_) => Poly::factor(expr.clone()),
+        Expr::Var(name) => env.get(name).map(Poly::constant).unwrap_or_else(|| Poly::factor(expr.clone())),
+        Expr::Neg(x) => {
+            let x = to_poly(x, env);
+            x.neg().unwrap_or_else(|| Poly::factor(Expr::Neg(Box::new(x.to_expr()))))
+        }
+        Expr::Not(x) => {
+            let x = to_poly(x, env);
+            match x.as_constant() {
+                Some(n) => Poly::constant(!truthy(&n) as i64),
+                None => Poly::factor(Expr::Not(Box::new(x.to_expr())))
+            }
+        }
+        // A known left side may settle && and || on its own, as when
+        // evaluating
+        Expr::Bin(op, a, b) if op.is_logic() => {
+            let a = to_poly(a, env);
+            match a.as_constant().map(|n| truthy(&n)) {
+                Some(t) if t == (*op == Op::Or) => Poly::constant(t as i64),
+                _ => {
+                    let b = to_poly(b, env);
+                    let folded = match (a.as_constant(), b.as_constant()) {
+                        (Some(x), Some(y)) => op.apply(x, y).ok().map(Poly::constant),
+                        _ => None
+                    };
+                    folded.unwrap_or_else(|| Poly::factor(Expr::Bin(*op, Box::new(a.to_expr()), Box::new(b.to_expr()))))
+                }
+            }
+        }
+        Expr::Bin(op, a, b) => {
+            let (a, b) = (to_poly(a, env), to_poly(b, env));
+            let folded = match op {
+                Op::Add => a.add(&b),
+                Op::Sub => b.neg().and_then(|b| a.add(&b)),
+                Op::Mul => a.mul(&b),
+                Op::Pow => b.as_constant().and_then(|e| u32::try_from(e).ok()).and_then(|e| a.pow(e)),
+                Op::Div | Op::Mod => match (a.as_constant(), b.as_constant()) {
+                    // Only exact quotients are folded, so the result holds for
+                    // number types with fractions too
+                    (Some(x), Some(y)) if *op == Op::Div && y != 0 && x % y != 0 => None,
+                    // Errors such as dividing by zero are left for evaluation
+                    (Some(x), Some(y)) => op.apply(x, y).ok().map(Poly::constant),
+                    (_, Some(1)) if *op == Op::Div => Some(a.clone()),
+                    // x % 1 is only 0 for whole x, which an unknown may not be
+                    _ => None
+                },
+                _ => match (a.as_constant(), b.as_constant()) {
+                    (Some(x), Some(y)) => op.apply(x, y).ok().map(Poly::constant),
+                    _ => None
+                }
+            };
+            folded.unwrap_or_else(|| Poly::factor(Expr::Bin(*op, Box::new(a.to_expr()), Box::new(b.to_expr()))))
+        }
+        Expr::Cond(c, a, b) => {
+            let c = to_poly(c, env);
+            match c.as_constant() {
+                Some(n) if truthy(&n) => to_poly(a, env),
+                Some(_) => to_poly(b, env),
+                None => Poly::factor(Expr::Cond(
+                    Box::new(c.to_expr()), Box::new(simplify(a, env)), Box::new(simplify(b, env))))
+            }
+        }
+        Expr::Call(name, args) => {
+            let args: Vec<Poly> = args.iter().map(|a| to_poly(a, env)).collect();
+            let values: Option<Vec<i64>> = args.iter().map(|a| a.as_constant()).collect();
+            values.and_then(|v| env.call(name, &v).ok())
+                .map(Poly::constant)
+                .unwrap_or_else(|| Poly::factor(Expr::Call(name.clone(), args.iter().map(|a| a.to_expr()).collect())))
+        }
+    }
+}
+
+// Fold constants, substituting any variables bound in `env`, and collect like
+// terms into a polynomial. Multiplying by zero drops the other side even if
+// evaluating it would have failed.
+pub fn simplify(expr: &Expr, env: &Env) -> Expr {
+    to_poly(expr, env).to_expr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::prec::PrecTable;
+
+    fn simp(expr: &str, table: &PrecTable, env: &Env) -> String {
+        let ast = Expr::build(&parse(expr).unwrap(), table).unwrap();
+        simplify(&ast, env).pretty(table)
+    }
+
+    #[test]
+    fn test_identities() {
+        let std = PrecTable::standard();
+        let env: Env = Env::new();
+        assert_eq!(simp("x * 1 + 0", &std, &env), "x");
+        assert_eq!(simp("(x - x) * y + 3 * 0", &std, &env), "0");
+        assert_eq!(simp("x * 0 + 2 ^ 3 - 1", &std, &env), "7");
+        assert_eq!(simp("x / 1 + 3 % 1", &std, &env), "x");
+        assert_eq!(simp("y % 1", &std, &env), "y % 1");
+        // Only whole numbers vanish under % 1, which 5 / 2 is not
+        assert_eq!(simp("5 / 2 % 1 + x", &std, &env), "x + 5 / 2 % 1");
+        assert_eq!(simp("-(-x)", &std, &env), "x");
+        assert_eq!(simp("1 / 0 + 2 * x", &std, &env), "2 * x + 1 / 0");
+        assert_eq!(simp("6 / 3 + 1 / 2", &std, &env), "1 / 2 + 2");
+    }
+
+    #[test]
+    fn test_like_terms() {
+        let std = PrecTable::standard();
+        let p2 = PrecTable::addition_first();
+        let env: Env = Env::new();
+        assert_eq!(simp("2 * x + 3 * x - x", &std, &env), "4 * x");
+        assert_eq!(simp("x * y + y * x + 2 * 3", &std, &env), "2 * x * y + 6");
+        assert_eq!(simp("(x + 1) * (x - 1)", &std, &env), "x ^ 2 - 1");
+        assert_eq!(simp("(x + 1) ^ 2 - x * x", &std, &env), "2 * x + 1");
+        assert_eq!(simp("1 - x * x * x", &std, &env), "-x ^ 3 + 1");
+        assert_eq!(simp("x / y + x / y", &std, &env), "2 * (x / y)");
+        assert_eq!(simp("max(x, 1) - 2 * max(x, 1)", &std, &env), "-max(x, 1)");
+
+        // Grouping comes from the table the expression was read with
+        assert_eq!(simp("2 * x + 3", &p2, &env), "(2 * x) + 6");
+    }
+
+    #[test]
+    fn test_logic() {
+        let std = PrecTable::standard();
+        let mut env: Env = Env::new();
+        env.set("y", 2);
+        assert_eq!(simp("y > 1 && x", &std, &env), "1 && x");
+        assert_eq!(simp("y < 1 && x / 0", &std, &env), "0");
+        assert_eq!(simp("y || x", &std, &env), "1");
+        assert_eq!(simp("!(y == 2) + x", &std, &env), "x");
+        assert_eq!(simp("y == 2 ? x + x : 1 / 0", &std, &env), "2 * x");
+        assert_eq!(simp("x ? y * 3 : !x", &std, &env), "x ? 6 : !x");
+        assert_eq!(simp("(x ? 1 : 2) * 2 + (x ? 1 : 2)", &std, &env), "3 * (x ? 1 : 2)");
+    }
+
+    #[test]
+    fn test_partially_known() {
+        let std = PrecTable::standard();
+        let mut env: Env = Env::new();
+        env.set("y", 2);
+        assert_eq!(simp("x * y + y ^ 3", &std, &env), "2 * x + 8");
+        assert_eq!(simp("max(y, 3) * x + abs(z - y)", &std, &env), "3 * x + abs(z - 2)");
+        assert_eq!(simp("x / (y - 2)", &std, &env), "x / 0");
+
+        // Simplifying doesn't change the value once everything is known
+        let e = "(x + y) * (x - 2 * y) + x / y";
+        let partial = Expr::build(&parse(e).unwrap(), &std).unwrap();
+        let partial = simplify(&partial, &env);
+        env.set("x", 7);
+        assert_eq!(partial.eval(&env), Ok(9 * 3 + 3));
+    }
+}
@@ -0,0 +1,528 @@
+use crate::ast::Expr;
+use crate::env::Env;
+use crate::error::{Error, EvalError};
+use crate::lexer::Span;
+use crate::num::{truthy, Number};
+use crate::parser::{parse, Token};
+use crate::prec::{Assoc, Op, PrecTable};
+
+// The expression part way through evaluation. Brackets are kept so each step
+// reads like the input with one operation worked out.
+enum Item<N> {
+    Val(N),
+    Var(String),
+    Op(Op),
+    Neg,
+    Not,
+    Question,
+    Colon,
+    Call(String),
+    Comma,
+    Open,
+    Close
+}
+
+fn is_integer(shown: &str) -> bool {
+    let digits = shown.strip_prefix('-').unwrap_or(shown);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+struct Tracer<'t, N> {
+    items: Vec<(Item<N>, Span)>,
+    table: &'t PrecTable,
+    env: &'t Env<N>
+}
+
+impl<'t, N: Number> Tracer<'t, N> {
+    fn is_val(&self, i: usize) -> bool {
+        matches!(self.items.get(i), Some((Item::Val(_), _)))
+    }
+
+    // Precedence of the binary operator at i, if there is one before `end`
+    fn prec_at(&self, i: usize, end: usize) -> Option<i32> {
+        match self.items.get(i) {
+            Some((Item::Op(op), _)) if i < end => Some(self.table.get(*op).0),
+            _ => None
+        }
+    }
+
+    // The contents of the first bracket group to close, or everything if
+    // there are no brackets left
+    fn innermost(&self) -> (usize, usize) {
+        match self.items.iter().position(|(item, _)| matches!(item, Item::Close)) {
+            Some(close) => {
+                let open = self.items[..close].iter().rposition(|(item, _)| matches!(item, Item::Open))
+                    .expect("Unbalanced brackets");
+                (open + 1, close)
+            }
+            None => (0, self.items.len())
+        }
+    }
+
+    // The part to work on next: the first bracket group to close, or, if the
+    // guard at `limit` comes first, the group around it. Only operators
+    // before the returned stop can be worked out.
+    fn segment(&self, limit: usize) -> (usize, usize, usize) {
+        if self.items[..limit].iter().any(|(item, _)| matches!(item, Item::Close)) {
+            let (start, end) = self.innermost();
+            return (start, end, end);
+        }
+        let start = self.items[..limit].iter().rposition(|(item, _)| matches!(item, Item::Open))
+            .map(|open| open + 1).unwrap_or(0);
+        (start, self.operand_end(limit, |_| false), limit)
+    }
+
+    // The first item after `from` at the same bracket depth that `stops` the
+    // operand there, or the ')' or ',' ending its group
+    fn operand_end<S: FnMut(&Item<N>) -> bool>(&self, from: usize, mut stops: S) -> usize {
+        let mut depth = 0;
+        for i in from..self.items.len() {
+            match &self.items[i].0 {
+                Item::Open => depth += 1,
+                Item::Close | Item::Comma if depth == 0 => return i,
+                Item::Close => depth -= 1,
+                item if depth == 0 && stops(item) => return i,
+                _ => ()
+            }
+        }
+        self.items.len()
+    }
+
+    // The end of a ternary branch starting at `from`, skipping over any
+    // ternary nested inside it
+    fn branch_end(&self, from: usize) -> usize {
+        let mut nested = 0;
+        self.operand_end(from, |item| match item {
+            Item::Question => {
+                nested += 1;
+                false
+            }
+            Item::Colon if nested == 0 => true,
+            Item::Colon => {
+                nested -= 1;
+                false
+            }
+            _ => false
+        })
+    }
+
+    // Whether i is a &&, || or '?', which decide if their next operand is
+    // needed at all
+    fn is_guard(&self, i: usize) -> bool {
+        match &self.items[i].0 {
+            Item::Op(op) => op.is_logic(),
+            Item::Question => true,
+            _ => false
+        }
+    }
+
+    // The truth of the guard's test, once it is down to a single value
+    fn test(&self, i: usize) -> Option<bool> {
+        let n = match &self.items[..i].last() {
+            Some((Item::Val(n), _)) => n,
+            _ => return None
+        };
+        let whole = i < 2 || match (&self.items[i].0, &self.items[i - 2].0) {
+            (_, Item::Open) | (_, Item::Comma) | (_, Item::Question) | (_, Item::Colon) => true,
+            (Item::Op(op), Item::Neg) | (Item::Op(op), Item::Not) => self.table.get(*op).0 >= self.table.neg,
+            (Item::Op(op), Item::Op(left)) => {
+                let (prec, _) = self.table.get(*op);
+                let (lp, la) = self.table.get(*left);
+                lp < prec || (lp == prec && la == Assoc::Right)
+            }
+            _ => false
+        };
+        if whole { Some(truthy(n)) } else { None }
+    }
+
+    // The first guard whose test still needs working out. Nothing after it
+    // can be touched yet, as it may turn out not to be needed.
+    fn limit(&self) -> usize {
+        (0..self.items.len()).find(|&i| self.is_guard(i) && self.test(i).is_none())
+            .unwrap_or(self.items.len())
+    }
+
+    // Drop the operand of the first guard whose test makes it unnecessary:
+    // the right side of a false && or a true ||, or the branch a ternary
+    // doesn't take
+    fn prune(&mut self) -> bool {
+        for i in 0..self.limit() {
+            let t = match self.test(i) {
+                Some(t) if self.is_guard(i) => t,
+                _ => continue
+            };
+            match self.items[i].0 {
+                Item::Op(op) if t == (op == Op::Or) => {
+                    // The operand runs until an operator that, once the ones
+                    // waiting inside it have been applied, would apply `op`.
+                    // Prefix operators wait too, so they can carry a looser
+                    // operator into the operand.
+                    let table = self.table;
+                    let mut waiting: Vec<Option<Op>> = Vec::new();
+                    let end = self.operand_end(i + 1, |item| match item {
+                        Item::Neg | Item::Not => {
+                            waiting.push(None);
+                            false
+                        }
+                        Item::Op(right) => {
+                            let (prec, _) = table.get(*right);
+                            while waiting.last().map(|&w| table.binds_first(w, prec)).unwrap_or(false) {
+                                waiting.pop();
+                            }
+                            if waiting.is_empty() && table.binds_first(Some(op), prec) {
+                                return true;
+                            }
+                            waiting.push(Some(*right));
+                            false
+                        }
+                        Item::Question | Item::Colon => true,
+                        _ => false
+                    });
+                    self.items.drain(i..end);
+                    self.items[i - 1].0 = Item::Val(N::from_i64(t as i64));
+                }
+                Item::Question => {
+                    let colon = self.branch_end(i + 1);
+                    let end = self.branch_end(colon + 1);
+                    if t {
+                        self.items.drain(colon..end);
+                        self.items.drain(i - 1..=i);
+                    } else {
+                        self.items.drain(i - 1..=colon);
+                    }
+                }
+                _ => continue
+            }
+            return true;
+        }
+        false
+    }
+
+    // Look up the variables before the limit, once pruning has dropped any
+    // that aren't needed. The ones after it may never be.
+    fn resolve(&mut self) -> Result<bool, Error> {
+        let limit = self.limit();
+        let env = self.env;
+        let mut resolved = false;
+        for (item, span) in self.items[..limit].iter_mut() {
+            if let Item::Var(name) = item {
+                let value = env.get(name).ok_or_else(|| Error::Eval(EvalError::UndefinedVar(name.clone()), *span))?;
+                *item = Item::Val(value);
+                resolved = true;
+            }
+        }
+        Ok(resolved)
+    }
+
+    // Whether the group starting at `start` holds a call's arguments
+    fn is_call(&self, start: usize) -> bool {
+        start > 1 && matches!(self.items[start - 2].0, Item::Call(_))
+    }
+
+    // Drop brackets that are down to a single value
+    fn unwrap_groups(&mut self) {
+        loop {
+            let (start, end) = self.innermost();
+            if start == 0 || end - start != 1 || self.is_call(start) {
+                return;
+            }
+            self.items.remove(end);
+            self.items.remove(start - 1);
+        }
+    }
+
+    // The first operator in start..stop that can be worked out now, meaning
+    // its operands are plain values and neither neighbour binds before it
+    fn redex(&self, start: usize, end: usize, stop: usize) -> Option<usize> {
+        (start..stop).find(|&i| match &self.items[i].0 {
+            Item::Neg | Item::Not => self.is_val(i + 1)
+                && self.prec_at(i + 2, end).map(|p| p < self.table.neg).unwrap_or(true),
+            Item::Op(op) => {
+                if i == start || !self.is_val(i - 1) || !self.is_val(i + 1) {
+                    return false;
+                }
+                let (prec, assoc) = self.table.get(*op);
+                let left_ok = i < start + 2 || match &self.items[i - 2].0 {
+                    Item::Neg | Item::Not => prec >= self.table.neg,
+                    Item::Op(left) => {
+                        let (lp, la) = self.table.get(*left);
+                        lp < prec || (lp == prec && la == Assoc::Right)
+                    }
+                    _ => true
+                };
+                let right_ok = match self.prec_at(i + 2, end) {
+                    Some(rp) => prec > rp || (prec == rp && assoc == Assoc::Left),
+                    None => true
+                };
+                left_ok && right_ok
+            }
+            _ => false
+        })
+    }
+
+    fn reduce(&mut self, i: usize) -> Result<(), Error> {
+        if let (Item::Neg, span) | (Item::Not, span) = &self.items[i] {
+            let span = *span;
+            let not = matches!(self.items[i].0, Item::Not);
+            let (x, x_span) = self.items.remove(i + 1);
+            let x = match x {
+                Item::Val(x) if not => N::from_i64(!truthy(&x) as i64),
+                Item::Val(x) => x.negate().map_err(|e| Error::Eval(e, span))?,
+                _ => unreachable!("Negating a non-value")
+            };
+            self.items[i] = (Item::Val(x), span.to(x_span));
+            return Ok(());
+        }
+
+        let mut three = self.items.drain(i - 1..i + 2);
+        let (a, b, op, span, op_span) = match (three.next(), three.next(), three.next()) {
+            (Some((Item::Val(a), a_span)), Some((Item::Op(op), op_span)), Some((Item::Val(b), b_span))) => {
+                (a, b, op, a_span.to(b_span), op_span)
+            }
+            _ => unreachable!("Reducing a non-operator")
+        };
+        drop(three);
+        let value = op.apply(a, b).map_err(|e| Error::Eval(e, op_span))?;
+        self.items.insert(i - 1, (Item::Val(value), span));
+        Ok(())
+    }
+
+    // Call the function whose arguments fill start..end, once they are all
+    // values
+    fn reduce_call(&mut self, start: usize, end: usize) -> Result<bool, Error> {
+        if !self.is_call(start) || self.items[start..end].iter().any(|(item, _)| !matches!(item, Item::Val(_) | Item::Comma)) {
+            return Ok(false);
+        }
+
+        let span = self.items[start - 2].1.to(self.items[end].1);
+        let mut name = String::new();
+        let mut args = Vec::new();
+        for (item, _) in self.items.drain(start - 2..=end) {
+            match item {
+                Item::Call(n) => name = n,
+                Item::Val(n) => args.push(n),
+                _ => ()
+            }
+        }
+        let value = self.env.call(&name, &args).map_err(|e| Error::Eval(e, span))?;
+        self.items.insert(start - 2, (Item::Val(value), span));
+        Ok(true)
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (i, (item, _)) in self.items.iter().enumerate() {
+            match item {
+                // A fraction like 2/3 would read as a division, and a negative
+                // value must not take the next operator with it
+                Item::Val(n) if self.items.len() > 1 && !is_integer(&n.to_string()) => {
+                    out.push_str(&format!("({})", n))
+                }
+                Item::Val(n) if n.to_string().starts_with('-')
+                    && self.prec_at(i + 1, self.items.len()).map(|p| p >= self.table.neg).unwrap_or(false) => {
+                    out.push_str(&format!("({})", n))
+                }
+                Item::Val(n) => out.push_str(&n.to_string()),
+                Item::Var(name) => out.push_str(name),
+                Item::Op(op) => out.push_str(&format!(" {} ", op.symbol())),
+                Item::Neg => out.push('-'),
+                Item::Not => out.push('!'),
+                Item::Question => out.push_str(" ? "),
+                Item::Colon => out.push_str(" : "),
+                Item::Call(name) => out.push_str(name),
+                Item::Comma => out.push_str(", "),
+                Item::Open => out.push('('),
+                Item::Close => out.push(')')
+            }
+        }
+        out
+    }
+}
+
+// Evaluate one operation at a time, innermost brackets first, giving the
+// expression after each step. Operands that && || and ?: don't need are
+// dropped without being worked out. The last entry is the result.
+pub fn trace<N: Number>(tokens: &[Token], table: &PrecTable, env: &Env<N>) -> Result<Vec<String>, Error> {
+    // Stepping assumes operands and operators alternate, which only folding
+    // checks
+    Expr::build(tokens, table)?;
+    let mut items = Vec::with_capacity(tokens.len() * 2);
+    for token in tokens {
+        match token {
+            Token::Number(n, span) => items.push((Item::Val(N::from_i64(*n)), *span)),
+            Token::Decimal(d, span) => {
+                items.push((Item::Val(N::from_decimal(*d).map_err(|e| Error::Eval(e, *span))?), *span));
+            }
+            Token::Var(name, span) => items.push((Item::Var(name.clone()), *span)),
+            Token::Op(op, span) => items.push((Item::Op(*op), *span)),
+            Token::Neg(span) => items.push((Item::Neg, *span)),
+            Token::Not(span) => items.push((Item::Not, *span)),
+            Token::Question(span) => items.push((Item::Question, *span)),
+            Token::Colon(span) => items.push((Item::Colon, *span)),
+            Token::Call(name, _, span) => items.push((Item::Call(name.clone()), *span)),
+            Token::Comma(span) => items.push((Item::Comma, *span)),
+            Token::LParen(span) => items.push((Item::Open, Span::new(span.start, span.start + 1))),
+            Token::RParen(span) => items.push((Item::Close, *span))
+        }
+    }
+
+    let mut tracer = Tracer { items, table, env };
+    let mut steps = Vec::new();
+    let mut last = tracer.render();
+    loop {
+        tracer.unwrap_groups();
+        let (start, end, stop) = tracer.segment(tracer.limit());
+        let reduced = if tracer.prune() {
+            true
+        } else if tracer.resolve()? {
+            // The limit may have moved, so look again before reducing. Only
+            // a lookup that gives the result is a step of its own.
+            if tracer.items.len() > 1 {
+                continue;
+            }
+            true
+        } else {
+            match tracer.redex(start, end, stop) {
+                Some(i) => {
+                    tracer.reduce(i)?;
+                    true
+                }
+                None if stop == end => tracer.reduce_call(start, end)?,
+                None => false
+            }
+        };
+        match reduced {
+            true => {
+                tracer.unwrap_groups();
+                // Negating a literal looks the same before and after
+                let step = tracer.render();
+                if step != last {
+                    steps.push(step.clone());
+                    last = step;
+                }
+            }
+            false => break
+        }
+    }
+
+    // Nothing left to work out means a lone value, possibly never reduced
+    if tracer.items.len() != 1 {
+        let span = tracer.items[0].1.to(tracer.items[tracer.items.len() - 1].1);
+        return Err(Error::Eval(EvalError::TraceStuck(tracer.render()), span));
+    }
+    if steps.is_empty() {
+        steps.push(tracer.render());
+    }
+    Ok(steps)
+}
+
+pub fn trace_str<N: Number>(expr: &str, table: &PrecTable) -> Result<Vec<String>, Error> {
+    trace(&parse(expr)?, table, &Env::<N>::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_rational::BigRational;
+
+    use crate::{eval_expr, eval_with};
+
+    fn steps(expr: &str, table: &PrecTable) -> Vec<String> {
+        trace_str::<i64>(expr, table).unwrap()
+    }
+
+    #[test]
+    fn test_trace() {
+        let p1 = PrecTable::left_to_right();
+        let p2 = PrecTable::addition_first();
+        let std = PrecTable::standard();
+
+        assert_eq!(steps("2 * 3 + (4 * 5)", &p1), vec!["2 * 3 + 20", "6 + 20", "26"]);
+        assert_eq!(steps("2 * 3 + (4 * 5)", &p2), vec!["2 * 3 + 20", "2 * 23", "46"]);
+        assert_eq!(steps("2 ^ 3 ^ 2", &std), vec!["2 ^ 9", "512"]);
+        assert_eq!(steps("-2 ^ 2 + ((1))", &std), vec!["-4 + 1", "-3"]);
+        assert_eq!(steps("3 - -(1 + 1) * 4", &std), vec!["3 - -2 * 4", "3 - -8", "11"]);
+        assert_eq!(steps("(0 - 2) ^ 2", &std), vec!["(-2) ^ 2", "4"]);
+        assert_eq!(steps("(7)", &std), vec!["7"]);
+        assert_eq!(steps("max(1 + 2, -abs(-4)) * 2", &std),
+            vec!["max(1 + 2, -4) * 2", "max(3, -4) * 2", "3 * 2", "6"]);
+
+        // Operands that aren't needed are dropped without being worked out
+        assert_eq!(steps("1 + 1 == 3 && 1 / 0", &std), vec!["2 == 3 && 1 / 0", "0 && 1 / 0", "0"]);
+        assert_eq!(steps("2 > 1 || (1 / 0)", &std), vec!["1 || (1 / 0)", "1"]);
+        assert_eq!(steps("!(1 && 5 - 5)", &std), vec!["!(1 && 0)", "!0", "1"]);
+        assert_eq!(steps("0 ? (1 / 0) : 1 ? 2 * 3 : 4", &std), vec!["1 ? 2 * 3 : 4", "2 * 3", "6"]);
+        assert_eq!(steps("(1 < 2 ? 3 : 4) * 5", &std), vec!["(1 ? 3 : 4) * 5", "3 * 5", "15"]);
+
+        let mut env: Env = Env::new();
+        env.set("x", 5);
+        let tokens = parse("x * (x - 1)").unwrap();
+        assert_eq!(trace(&tokens, &std, &env), Ok(vec!["5 * 4".to_string(), "20".to_string()]));
+        // Variables are only looked up once they're known to be needed
+        let tokens = parse("1 || y").unwrap();
+        assert_eq!(trace(&tokens, &std, &env), Ok(vec!["1".to_string()]));
+        assert_eq!(eval_expr(&tokens, &std, &env), Ok(1));
+        let tokens = parse("x - 4 && y").unwrap();
+        assert_eq!(trace(&tokens, &std, &env), Err(Error::Eval(EvalError::UndefinedVar("y".to_string()), Span::new(9, 10))));
+        let tokens = parse("(x + 1 > 2) * 2 || y").unwrap();
+        assert_eq!(trace(&tokens, &std, &env).unwrap()[0], "(6 > 2) * 2 || y");
+        // A variable left on its own is looked up as the last step
+        let tokens = parse("0 ? 1 : x").unwrap();
+        assert_eq!(trace(&tokens, &std, &env), Ok(vec!["x".to_string(), "5".to_string()]));
+        let tokens = parse("1 ? x : 2").unwrap();
+        assert_eq!(trace(&tokens, &std, &env), Ok(vec!["x".to_string(), "5".to_string()]));
+        assert_eq!(trace(&parse("x").unwrap(), &std, &env), Ok(vec!["5".to_string()]));
+        assert_eq!(trace_str::<i64>("1 + 2 / (3 - 3)", &std),
+            Err(Error::Eval(EvalError::DivisionByZero, Span::new(6, 7))));
+        // Caught before stepping, which would otherwise get stuck
+        assert!(trace_str::<i64>("(1 + * 9) + 2", &std).is_err());
+        assert!(trace_str::<i64>("1 2", &std).is_err());
+    }
+
+    #[test]
+    fn test_trace_prefix_scope() {
+        // A prefix operator looser than && or || carries a looser operator
+        // into the operand it drops
+        let table: PrecTable = "+:1:r,*:1:r,&&:5,||:6,==:3:r".parse().unwrap();
+        let mut env: Env = Env::new();
+        env.set("x", 2);
+        env.set("y", 1);
+        for e in &["1 || -3 == x + 1", "y && !x == y", "0 && -1 == 1 / 0 + 1", "1 || !x == 1 / 0"] {
+            let tokens = parse(e).unwrap();
+            let want = eval_expr(&tokens, &table, &env).map(|n| n.to_string());
+            assert_eq!(trace(&tokens, &table, &env).map(|t| t.last().unwrap().clone()), want, "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_trace_fractions() {
+        // Values that aren't whole numbers are bracketed so they read as one
+        let std = PrecTable::standard();
+        let steps = trace_str::<BigRational>("(2 / 3) ^ 2 - 1", &std).unwrap();
+        assert_eq!(steps, vec!["(2/3) ^ 2 - 1", "(4/9) - 1", "-5/9"]);
+        assert_eq!(trace_str::<f64>("1.5 * 2 + 0.25", &std).unwrap(), vec!["3 + (0.25)", "3.25"]);
+    }
+
+    #[test]
+    fn test_trace_steps_agree() {
+        // Every step evaluates to the final answer, and each does at most one
+        // operation
+        let tables = [PrecTable::left_to_right(), PrecTable::addition_first(), PrecTable::standard()];
+        let exprs = include_str!("../input.txt").lines().filter(|l| !l.trim().is_empty())
+            .chain(vec!["-(1 - 2) ^ 3 % (4 / -3)", "2 ^ 3 ^ (2 - 1) * -(-4)", "1 + 2 > 3 || !(4 == 4) && 5 < 6",
+                "(1 < 0 ? 2 : 3) ? 4 - 1 ? 5 : 6 : max(7, 0 && 8)"]);
+        for e in exprs {
+            let ops = parse(e).unwrap().iter()
+                .filter(|t| matches!(t, Token::Op(..) | Token::Neg(_) | Token::Not(_) | Token::Question(_) | Token::Call(..)))
+                .count();
+            for table in tables.iter() {
+                let trace = steps(e, table);
+                let answer = eval_with(e, table).unwrap();
+                assert!(trace.len() <= ops, "{}", e);
+                for step in trace.iter() {
+                    assert_eq!(eval_with(step, table), Ok(answer), "{} stepped to {}", e, step);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::cell::RefCell;
+use std::fmt::Write;
+
+use crate::env::Env;
+use crate::error::{Error, EvalError};
+use crate::lexer::Span;
+use crate::num::{truthy, Decimal, Number};
+use crate::parser::{fold_expr, parse, Fold, Guard, Token};
+use crate::prec::{Op, PrecTable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instr {
+    Push(i64),
+    Load(usize),
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Not,
+    Eq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    // Turn the top of the stack into 1 or 0
+    Bool,
+    // Function number and argument count
+    Call(usize, usize),
+    Jump(usize),
+    // Pops the condition
+    JumpIfFalse(usize),
+    // Jump with the result if the top of the stack already decides the
+    // && or ||, otherwise pop it and carry on to the right operand
+    AndThen(usize),
+    OrElse(usize)
+}
+
+impl Instr {
+    fn from_op(op: Op) -> Instr {
+        match op {
+            Op::Add => Instr::Add,
+            Op::Sub => Instr::Sub,
+            Op::Mul => Instr::Mul,
+            Op::Div => Instr::Div,
+            Op::Mod => Instr::Mod,
+            Op::Pow => Instr::Pow,
+            Op::Eq => Instr::Eq,
+            Op::Lt => Instr::Lt,
+            Op::Gt => Instr::Gt,
+            Op::Le => Instr::Le,
+            Op::Ge => Instr::Ge,
+            Op::And | Op::Or => unreachable!("{:?} compiles to jumps", op)
+        }
+    }
+}
+
+// Compiled expression. Variables are numbered in order of first use and
+// `vars` gives their names, so a program can be run over many sets of values
+// without looking names up each time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    code: Vec<Instr>,
+    // Source span for each instruction, for error reporting
+    spans: Vec<Span>,
+    vars: Vec<String>,
+    var_spans: Vec<Span>,
+    funcs: Vec<String>,
+    max_stack: usize
+}
+
+// Fold calls arrive in postfix order, so each piece is appended to the end of
+// the code as it comes. The fold result is the stack depth the piece needs.
+struct Compiler {
+    code: RefCell<Vec<Instr>>,
+    spans: RefCell<Vec<Span>>,
+    vars: RefCell<Vec<(String, Span)>>,
+    funcs: RefCell<Vec<String>>,
+    // Jumps still waiting to learn where their target is
+    jumps: RefCell<Vec<usize>>
+}
+
+impl Compiler {
+    fn emit(&self, instr: Instr, span: Span) {
+        self.code.borrow_mut().push(instr);
+        self.spans.borrow_mut().push(span);
+    }
+
+    // Emit a jump to be patched later
+    fn emit_jump(&self, instr: Instr, span: Span) {
+        self.jumps.borrow_mut().push(self.code.borrow().len());
+        self.emit(instr, span);
+    }
+
+    // Point the innermost unpatched jump at the next instruction
+    fn patch(&self) {
+        let at = self.jumps.borrow_mut().pop().expect("No jump to patch");
+        self.patch_at(at);
+    }
+
+    fn patch_at(&self, at: usize) {
+        let mut code = self.code.borrow_mut();
+        let target = code.len();
+        code[at] = match code[at] {
+            Instr::Jump(_) => Instr::Jump(target),
+            Instr::JumpIfFalse(_) => Instr::JumpIfFalse(target),
+            Instr::AndThen(_) => Instr::AndThen(target),
+            Instr::OrElse(_) => Instr::OrElse(target),
+            instr => unreachable!("{:?} is not a jump", instr)
+        };
+    }
+}
+
+impl Fold for Compiler {
+    type Out = usize;
+
+    fn num(&self, n: i64, span: Span) -> Result<usize, Error> {
+        self.emit(Instr::Push(n), span);
+        Ok(1)
+    }
+
+    // The VM only runs on i64
+    fn decimal(&self, d: Decimal, span: Span) -> Result<usize, Error> {
+        Err(Error::Eval(EvalError::Fraction(d.to_string()), span))
+    }
+
+    fn var(&self, name: &str, span: Span) -> Result<usize, Error> {
+        let slot = {
+            let mut vars = self.vars.borrow_mut();
+            match vars.iter().position(|(v, _)| v == name) {
+                Some(slot) => slot,
+                None => {
+                    vars.push((name.to_string(), span));
+                    vars.len() - 1
+                }
+            }
+        };
+        self.emit(Instr::Load(slot), span);
+        Ok(1)
+    }
+
+    fn neg(&self, depth: usize, span: Span) -> Result<usize, Error> {
+        self.emit(Instr::Neg, span);
+        Ok(depth)
+    }
+
+    fn not(&self, depth: usize, span: Span) -> Result<usize, Error> {
+        self.emit(Instr::Not, span);
+        Ok(depth)
+    }
+
+    // The left operand of && or || is popped before the right one runs
+    fn bin(&self, op: Op, a: usize, b: usize, span: Span) -> Result<usize, Error> {
+        if op.is_logic() {
+            self.emit(Instr::Bool, span);
+            self.patch();
+            return Ok(a.max(b));
+        }
+        self.emit(Instr::from_op(op), span);
+        // a's result sits on the stack while b runs
+        Ok(a.max(b + 1))
+    }
+
+    fn cond(&self, c: usize, a: usize, b: usize, _: Span) -> Result<usize, Error> {
+        self.patch();
+        Ok(c.max(a).max(b))
+    }
+
+    fn call(&self, name: &str, args: Vec<usize>, span: Span) -> Result<usize, Error> {
+        let slot = {
+            let mut funcs = self.funcs.borrow_mut();
+            match funcs.iter().position(|f| f == name) {
+                Some(slot) => slot,
+                None => {
+                    funcs.push(name.to_string());
+                    funcs.len() - 1
+                }
+            }
+        };
+        self.emit(Instr::Call(slot, args.len()), span);
+        // Each argument stays on the stack while the ones after it run
+        Ok(args.iter().enumerate().map(|(i, depth)| i + depth).max().unwrap_or(1))
+    }
+
+    // Jumps can't fail, so they just reuse the span before them
+    fn guard(&self, kind: Guard, _: &usize) {
+        let span = *self.spans.borrow().last().expect("Guard before any code");
+        match kind {
+            Guard::And => self.emit_jump(Instr::AndThen(0), span),
+            Guard::Or => self.emit_jump(Instr::OrElse(0), span),
+            Guard::Then => self.emit_jump(Instr::JumpIfFalse(0), span),
+            // Skip the else branch after the then branch, and send a false
+            // condition to the else branch
+            Guard::Else => {
+                let then = self.jumps.borrow_mut().pop().expect("Else without then");
+                self.emit_jump(Instr::Jump(0), span);
+                self.patch_at(then);
+            }
+        }
+    }
+}
+
+pub fn compile(tokens: &[Token], table: &PrecTable) -> Result<Program, Error> {
+    let compiler = Compiler {
+        code: RefCell::new(Vec::new()),
+        spans: RefCell::new(Vec::new()),
+        vars: RefCell::new(Vec::new()),
+        funcs: RefCell::new(Vec::new()),
+        jumps: RefCell::new(Vec::new())
+    };
+    let max_stack = fold_expr(tokens, table, &compiler)?;
+    let (vars, var_spans) = compiler.vars.into_inner().into_iter().unzip();
+
+    Ok(Program {
+        code: compiler.code.into_inner(),
+        spans: compiler.spans.into_inner(),
+        vars,
+        var_spans,
+        funcs: compiler.funcs.into_inner(),
+        max_stack
+    })
+}
+
+pub fn compile_str(expr: &str, table: &PrecTable) -> Result<Program, Error> {
+    compile(&parse(expr)?, table)
+}
+
+impl Program {
+    pub fn code(&self) -> &[Instr] {
+        &self.code
+    }
+
+    pub fn vars(&self) -> &[String] {
+        &self.vars
+    }
+
+    // Look up the value for each variable slot
+    pub fn bind(&self, env: &Env) -> Result<Vec<i64>, Error> {
+        self.vars.iter().zip(self.var_spans.iter())
+            .map(|(name, span)| env.get(name)
+                .ok_or_else(|| Error::Eval(EvalError::UndefinedVar(name.clone()), *span)))
+            .collect()
+    }
+
+    pub fn run_env(&self, env: &Env) -> Result<i64, Error> {
+        self.exec(&self.bind(env)?, env)
+    }
+
+    // Run with `slots[i]` as the value of variable i. Only builtin functions
+    // are available.
+    pub fn run(&self, slots: &[i64]) -> Result<i64, Error> {
+        self.exec(slots, &Env::new())
+    }
+
+    fn exec(&self, slots: &[i64], env: &Env) -> Result<i64, Error> {
+        assert_eq!(slots.len(), self.vars.len(), "Wrong number of variable values");
+
+        let mut stack: Vec<i64> = Vec::with_capacity(self.max_stack);
+        let mut pc = 0;
+
+        while pc < self.code.len() {
+            let instr = self.code[pc];
+            pc += 1;
+            let op = match instr {
+                Instr::Push(n) => {
+                    stack.push(n);
+                    continue;
+                }
+                Instr::Load(slot) => {
+                    stack.push(slots[slot]);
+                    continue;
+                }
+                Instr::Neg => {
+                    let x = stack.pop().expect("VM stack underflow");
+                    stack.push(x.negate().map_err(|e| Error::Eval(e, self.spans[pc - 1]))?);
+                    continue;
+                }
+                Instr::Not => {
+                    let x = stack.pop().expect("VM stack underflow");
+                    stack.push(!truthy(&x) as i64);
+                    continue;
+                }
+                Instr::Bool => {
+                    let x = stack.pop().expect("VM stack underflow");
+                    stack.push(truthy(&x) as i64);
+                    continue;
+                }
+                Instr::Call(func, argc) => {
+                    let args = stack.split_off(stack.len() - argc);
+                    stack.push(env.call(&self.funcs[func], &args).map_err(|e| Error::Eval(e, self.spans[pc - 1]))?);
+                    continue;
+                }
+                Instr::Jump(target) => {
+                    pc = target;
+                    continue;
+                }
+                Instr::JumpIfFalse(target) => {
+                    if !truthy(&stack.pop().expect("VM stack underflow")) {
+                        pc = target;
+                    }
+                    continue;
+                }
+                Instr::AndThen(target) | Instr::OrElse(target) => {
+                    let x = stack.pop().expect("VM stack underflow");
+                    let t = truthy(&x);
+                    if t == matches!(instr, Instr::OrElse(_)) {
+                        stack.push(t as i64);
+                        pc = target;
+                    }
+                    continue;
+                }
+                Instr::Add => Op::Add,
+                Instr::Sub => Op::Sub,
+                Instr::Mul => Op::Mul,
+                Instr::Div => Op::Div,
+                Instr::Mod => Op::Mod,
+                Instr::Pow => Op::Pow,
+                Instr::Eq => Op::Eq,
+                Instr::Lt => Op::Lt,
+                Instr::Gt => Op::Gt,
+                Instr::Le => Op::Le,
+                Instr::Ge => Op::Ge
+            };
+
+            let b = stack.pop().expect("VM stack underflow");
+            let a = stack.pop().expect("VM stack underflow");
+            stack.push(op.apply(a, b).map_err(|e| Error::Eval(e, self.spans[pc - 1]))?);
+        }
+
+        Ok(stack.pop().expect("Program left nothing on the stack"))
+    }
+
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        for (pc, instr) in self.code.iter().enumerate() {
+            let _ = match instr {
+                Instr::Push(n) => writeln!(out, "{:04}  push {}", pc, n),
+                Instr::Load(slot) => writeln!(out, "{:04}  load {} ({})", pc, slot, self.vars[*slot]),
+                Instr::Neg => writeln!(out, "{:04}  neg", pc),
+                Instr::Add => writeln!(out, "{:04}  add", pc),
+                Instr::Sub => writeln!(out, "{:04}  sub", pc),
+                Instr::Mul => writeln!(out, "{:04}  mul", pc),
+                Instr::Div => writeln!(out, "{:04}  div", pc),
+                Instr::Mod => writeln!(out, "{:04}  mod", pc),
+                Instr::Pow => writeln!(out, "{:04}  pow", pc),
+                Instr::Not => writeln!(out, "{:04}  not", pc),
+                Instr::Eq => writeln!(out, "{:04}  eq", pc),
+                Instr::Lt => writeln!(out, "{:04}  lt", pc),
+                Instr::Gt => writeln!(out, "{:04}  gt", pc),
+                Instr::Le => writeln!(out, "{:04}  le", pc),
+                Instr::Ge => writeln!(out, "{:04}  ge", pc),
+                Instr::Bool => writeln!(out, "{:04}  bool", pc),
+                Instr::Call(func, argc) => writeln!(out, "{:04}  call {}/{}", pc, self.funcs[*func], argc),
+                Instr::Jump(target) => writeln!(out, "{:04}  jump {:04}", pc, target),
+                Instr::JumpIfFalse(target) => writeln!(out, "{:04}  jump_if_false {:04}", pc, target),
+                Instr::AndThen(target) => writeln!(out, "{:04}  and_then {:04}", pc, target),
+                Instr::OrElse(target) => writeln!(out, "{:04}  or_else {:04}", pc, target)
+            };
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval_p1, eval_p2};
+
+    const EXPRS: [&str; 8] = [
+        "2 * 3 + (4 * 5)",
+        "5 + (8 * 3 + 9 + 3 * 4 * 3)",
+        "5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))",
+        "((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2",
+        "6 * 2 * 6 + (8 + 8 * (5 + 4 * 7 + 6 + 6 + 3)) * 8",
+        "10 - 2 * 3 / -(4 - 6) % 5",
+        "-2 ^ 2 + 3 ^ 2 ^ 1",
+        "7"
+    ];
+
+    #[test]
+    fn test_vm_matches_eval() {
+        let p1 = PrecTable::left_to_right();
+        let p2 = PrecTable::addition_first();
+        let input = include_str!("../input.txt").lines().filter(|l| !l.trim().is_empty());
+        for e in EXPRS.iter().cloned().chain(input) {
+            assert_eq!(compile_str(e, &p1).unwrap().run(&[]), eval_p1(e), "{}", e);
+            assert_eq!(compile_str(e, &p2).unwrap().run(&[]), eval_p2(e), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_vm_variables() {
+        let prog = compile_str("x * (y + x) - 1", &PrecTable::addition_first()).unwrap();
+        assert_eq!(prog.vars(), &["x".to_string(), "y".to_string()]);
+        assert_eq!(prog.run(&[2, 3]), Ok(8));
+        assert_eq!(prog.run(&[3, 2]), Ok(12));
+
+        let mut env: Env = Env::new();
+        env.set("x", 4);
+        assert_eq!(prog.run_env(&env), Err(Error::Eval(EvalError::UndefinedVar("y".to_string()), Span::new(5, 6))));
+        env.set("y", 1);
+        assert_eq!(prog.run_env(&env), Ok(16));
+    }
+
+    #[test]
+    fn test_vm_errors() {
+        let prog = compile_str("1 + 2 / (x - 3)", &PrecTable::standard()).unwrap();
+        assert_eq!(prog.run(&[4]), Ok(3));
+        assert_eq!(prog.run(&[3]), Err(Error::Eval(EvalError::DivisionByZero, Span::new(6, 7))));
+    }
+
+    #[test]
+    fn test_vm_calls() {
+        let prog = compile_str("max(x, 2) * twice(x + 1)", &PrecTable::standard()).unwrap();
+        assert_eq!(prog.disassemble(), "\
+0000  load 0 (x)
+0001  push 2
+0002  call max/2
+0003  load 0 (x)
+0004  push 1
+0005  add
+0006  call twice/1
+0007  mul
+");
+        assert_eq!(prog.max_stack, 3);
+        assert_eq!(prog.run(&[5]), Err(Error::Eval(EvalError::UnknownFunction("twice".to_string()), Span::new(12, 24))));
+
+        let mut env: Env = Env::new();
+        env.set("x", 5);
+        env.define_fn("twice", 1, |args| Ok(args[0] * 2));
+        assert_eq!(prog.run_env(&env), Ok(60));
+    }
+
+    #[test]
+    fn test_vm_logic() {
+        let std = PrecTable::standard();
+        let exprs = [
+            "x > 2 && 10 / (x - 3) > 1 || !x",
+            "x == 0 ? 0 : x < 0 ? 0 - 1 : 1",
+            "(x ? 2 : 3) * (x >= 4 || 1 / 0)",
+            "max(x && 7, x <= 1 ? 5 : x)"
+        ];
+        for e in exprs.iter() {
+            let prog = compile_str(e, &std).unwrap();
+            for x in -2..6 {
+                let mut env: Env = Env::new();
+                env.set("x", x);
+                let tokens = parse(e).unwrap();
+                assert_eq!(prog.run(&[x]).ok(), crate::eval_expr(&tokens, &std, &env).ok(), "{} with x = {}", e, x);
+            }
+        }
+
+        let prog = compile_str("x && y ? 1 : 2", &std).unwrap();
+        assert_eq!(prog.disassemble(), "\
+0000  load 0 (x)
+0001  and_then 0004
+0002  load 1 (y)
+0003  bool
+0004  jump_if_false 0007
+0005  push 1
+0006  jump 0008
+0007  push 2
+");
+        assert_eq!(prog.run(&[0, 1]), Ok(2));
+        assert_eq!(prog.run(&[3, 1]), Ok(1));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let prog = compile_str("2 * 3 + -x", &PrecTable::left_to_right()).unwrap();
+        assert_eq!(prog.code(), &[
+            Instr::Push(2), Instr::Push(3), Instr::Mul, Instr::Load(0), Instr::Neg, Instr::Add
+        ]);
+        assert_eq!(prog.disassemble(), "\
+0000  push 2
+0001  push 3
+0002  mul
+0003  load 0 (x)
+0004  neg
+0005  add
+");
+        assert_eq!(prog.max_stack, 2);
+    }
+}
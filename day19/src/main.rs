@@ -1,143 +1,325 @@
 use regex::{Regex, Captures};
 use std::collections::HashMap;
+use std::rc::Rc;
 use lazy_static::lazy_static;
 
+// Rules are named by number, as in the puzzle, or by identifier
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum Rule {
-    Or(Vec<usize>, Vec<usize>),
-    Seq(Vec<usize>),
-    Char(char)
+    // Any number of alternative sequences, tried in order
+    Or(Vec<Vec<String>>),
+    Seq(Vec<String>),
+    // Characters to read in turn, one for each term
+    Lit(Vec<Term>),
+    // A rule matched at least min times, and at most max if there is a max
+    Repeat(String, usize, Option<usize>)
 }
 
-fn parse_nums(line: &str) -> Vec<usize> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"[0-9]+")
-            .expect("Regex compile failed");
+// What a single character of input has to be
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Term {
+    Char(char),
+    // Inclusive ranges, and whether the class is negated
+    Class(Vec<(char, char)>, bool),
+    Any
+}
+
+impl Term {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Term::Char(x) => *x == c,
+            Term::Class(ranges, negated) => ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated,
+            Term::Any => true
+        }
+    }
+}
+
+type Rules = HashMap<String, Rule>;
+
+// Characters with backslash escapes removed, each flagged if it was escaped.
+// A backslash takes whatever follows literally, so \" and \\ work.
+fn unescape(s: &str) -> Vec<(char, bool)> {
+    let mut out = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push((chars.next().expect("Regex allows no trailing backslash"), true)),
+            c => out.push((c, false))
+        }
+    }
+    out
+}
+
+// The inside of [...], where an unescaped '-' between two characters makes
+// a range
+fn parse_class(s: &str) -> Vec<(char, char)> {
+    let chars = unescape(s);
+    let mut ranges = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let lo = chars[i].0;
+        match chars.get(i + 1..i + 3) {
+            Some([('-', false), (hi, _)]) => {
+                assert!(lo <= *hi, "Backwards range {}-{} in class [{}]", lo, hi, s);
+                ranges.push((lo, *hi));
+                i += 3;
+            }
+            _ => {
+                ranges.push((lo, lo));
+                i += 1;
+            }
+        }
+    }
+    ranges
+}
+
+// A quoted string, a [...] class or '.'
+const TERM: &str = r#""(?:[^"\\]|\\.)*"|\[(?:[^\]\\]|\\.)+\]|\."#;
+
+// A rule reading characters, from a quoted string, a [...] class or '.'
+fn terminal(token: &str) -> Rule {
+    if let Some(quoted) = token.strip_prefix('"') {
+        let chars = unescape(&quoted[..quoted.len() - 1]);
+        Rule::Lit(chars.into_iter().map(|(c, _)| Term::Char(c)).collect())
+    } else if let Some(class) = token.strip_prefix('[') {
+        let class = &class[..class.len() - 1];
+        match class.strip_prefix('^') {
+            Some(rest) if !rest.is_empty() => Rule::Lit(vec![Term::Class(parse_class(rest), true)]),
+            _ => Rule::Lit(vec![Term::Class(parse_class(class), false)])
+        }
+    } else {
+        Rule::Lit(vec![Term::Any])
+    }
+}
+
+// Rule bodies built from names, '|', brackets, the quantifiers * + ? and
+// quoted strings, classes and '.' written in place. Each group, quantified
+// item and character rule in a body becomes a rule of its own, named after
+// the rule it is in and numbered, as in 'x/1'. Names can't contain '/', so
+// these never clash with the rules given.
+struct BodyParser<'a> {
+    name: &'a str,
+    tokens: Vec<&'a str>,
+    pos: usize,
+    rules: Vec<(String, Rule)>
+}
+
+impl<'a> BodyParser<'a> {
+    fn new(name: &'a str, body: &'a str) -> Self {
+        lazy_static! {
+            static ref TOKEN_R: Regex = Regex::new(&format!(r"{}|\w+|[()|*+?]", TERM))
+                .expect("Regex compile failed");
+        }
+        Self {
+            name,
+            tokens: TOKEN_R.find_iter(body).map(|m| m.as_str()).collect(),
+            pos: 0,
+            rules: Vec::new()
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn add(&mut self, rule: Rule) -> String {
+        let name = format!("{}/{}", self.name, self.rules.len() + 1);
+        self.rules.push((name.clone(), rule));
+        name
+    }
+
+    // The whole body, followed by the rules made for its parts
+    fn parse(mut self) -> Vec<(String, Rule)> {
+        let rule = self.alts();
+        if self.peek().is_some() {
+            panic!("Unmatched ')' in rule {}", self.name);
+        }
+        self.rules.insert(0, (self.name.to_string(), rule));
+        self.rules
+    }
+
+    // Alternatives separated by '|', up to a ')' or the end
+    fn alts(&mut self) -> Rule {
+        let mut alts = vec![self.seq()];
+        while self.peek() == Some("|") {
+            self.pos += 1;
+            alts.push(self.seq());
+        }
+        if alts.len() == 1 {
+            Rule::Seq(alts.remove(0))
+        } else {
+            Rule::Or(alts)
+        }
+    }
+
+    fn seq(&mut self) -> Vec<String> {
+        let mut seq = Vec::new();
+        while let Some(token) = self.peek() {
+            match token {
+                "|" | ")" => break,
+                "(" => {
+                    self.pos += 1;
+                    let group = self.alts();
+                    if self.peek() != Some(")") {
+                        panic!("Unclosed '(' in rule {}", self.name);
+                    }
+                    self.pos += 1;
+                    let group = self.add(group);
+                    seq.push(group);
+                }
+                "*" | "+" | "?" => {
+                    self.pos += 1;
+                    let item = seq.pop()
+                        .unwrap_or_else(|| panic!("Nothing for '{}' to repeat in rule {}", token, self.name));
+                    let (min, max) = match token {
+                        "*" => (0, None),
+                        "+" => (1, None),
+                        _ => (0, Some(1))
+                    };
+                    let repeat = self.add(Rule::Repeat(item, min, max));
+                    seq.push(repeat);
+                }
+                t if t.starts_with('"') || t.starts_with('[') || t == "." => {
+                    self.pos += 1;
+                    let lit = self.add(terminal(t));
+                    seq.push(lit);
+                }
+                name => {
+                    self.pos += 1;
+                    seq.push(name.to_string());
+                }
+            }
+        }
+        seq
     }
-    RE.find_iter(line)
-        .map(|m| m.as_str().parse::<usize>()
-            .expect("[0-9]+ matching string parse failed"))
-        .collect()
 }
 
 fn expect_cap<'a>(cap: &Captures<'a>, i: usize) -> &'a str {
     cap.get(i).expect("Defined capture group not present in match").as_str()
 }
 
-fn parse_rule(line: &str) -> (usize, Rule) {
+// The rule on a line, along with any rules made for its groups, repetitions
+// and the strings, classes and '.' written in it
+fn parse_rule(line: &str) -> Vec<(String, Rule)> {
     lazy_static! {
-        static ref OR_R: Regex = 
-            Regex::new(r"^([0-9]+): (([0-9 ]+)*) \| (([0-9 ]+)*)$").expect("Regex compile failed");
-        static ref SEQ_R: Regex = 
-            Regex::new(r"^([0-9]+):(( [0-9]+)*)$").expect("Regex compile failed");
-        static ref CHAR_R: Regex = 
-            Regex::new(r###"^([0-9]+): "([a-z])"$"###).expect("Regex compile failed");
-    }
-
-    if let Some(cap) = SEQ_R.captures(line) {
-        (
-            expect_cap(&cap, 1).parse::<usize>().unwrap(),
-            Rule::Seq(parse_nums(expect_cap(&cap, 2)))
-        )
-    } else if let Some(cap) = OR_R.captures(line) {
-        (
-            expect_cap(&cap, 1).parse::<usize>().unwrap(),
-            Rule::Or(parse_nums(expect_cap(&cap, 2)), parse_nums(expect_cap(&cap, 4)))
-        )
-    } else if let Some(cap) = CHAR_R.captures(line) {
-        (
-            expect_cap(&cap, 1).parse::<usize>().unwrap(),
-            Rule::Char(expect_cap(&cap, 2).chars().nth(0)
-                .expect("Regex has failed us."))
-        )
+        // A body that is a single string, class or '.' is the rule itself
+        static ref TERM_R: Regex = 
+            Regex::new(&format!(r"^(\w+): ({})$", TERM)).expect("Regex compile failed");
+        static ref EXPR_R: Regex = 
+            Regex::new(&format!(r"^(\w+):((?:[\w ()|*+?]|{})*)$", TERM)).expect("Regex compile failed");
+    }
+
+    if let Some(cap) = TERM_R.captures(line) {
+        vec![(expect_cap(&cap, 1).to_string(), terminal(expect_cap(&cap, 2)))]
+    } else if let Some(cap) = EXPR_R.captures(line) {
+        BodyParser::new(expect_cap(&cap, 1), expect_cap(&cap, 2)).parse()
     } else {
-        panic!("Rule line does not match any known format!");
+        panic!("Rule line '{}' does not match any known format!", line);
     }
 }
 
-fn parse_rules(contents: &str) -> HashMap<usize, Rule> {
-    contents.split("\n")
+fn parse_rules(contents: &str) -> Rules {
+    contents.split('\n')
         .map(|s| s.trim())
-        .filter(|s| s.len() > 0)
-        .map(parse_rule)
+        .filter(|s| !s.is_empty())
+        .flat_map(parse_rule)
         .collect()
 }
 
 #[derive(Clone)]
 struct PatternMachine {
-    rules: HashMap<usize, Rule>,
-    history: Vec<(usize, usize, usize)>, // History stack of rule, index, branch
-    rule: usize,  // Current rule
-    index: usize, // Index within the sequence
+    rules: Rc<Rules>, // Shared between the machines a branch splits into
+    history: Vec<(String, usize, usize, usize)>, // History stack of rule, index, branch, mark
+    rule: String, // Current rule
+    index: usize, // Index within the sequence, or times round a repetition
     branch: usize,
     branch_chosen: bool,
+    read: usize, // Characters read so far
+    mark: usize, // Characters read when the current repetition went round
     end: bool
 }
 
 impl PatternMachine {
-    fn new(rules: HashMap<usize, Rule>) -> Self {
+    fn new(rules: Rc<Rules>, start: &str) -> Self {
         Self {
-            rules: rules,
+            rules,
             history: Vec::new(),
             index: 0,
-            rule: 0,
+            rule: start.to_string(),
             branch: 0,
             branch_chosen: false,
+            read: 0,
+            mark: 0,
             end: false
         }
     }
 
+    fn current(&self) -> &Rule {
+        self.rules.get(&self.rule).unwrap_or_else(|| panic!("Bad rule name {}!", self.rule))
+    }
+
     fn is_end(&self) -> bool {
         self.end
     }
 
     fn is_branch(&self) -> bool {
-        let rule = self.rules.get(&self.rule).expect(format!("Bad rule index {}!", self.rule).as_str());
-        match rule {
-            Rule::Or(_, _) => true,
-            _ => false
+        self.branches() > 0
+    }
+
+    // How many alternatives the current rule has to choose from
+    fn branches(&self) -> usize {
+        match self.current() {
+            Rule::Or(alts) => alts.len(),
+            // Once more, or stop
+            Rule::Repeat(..) => 2,
+            _ => 0
         }
     }
 
-    // Take a branch, choosing branch index (0 or 1)
+    // Take a branch, choosing which alternative to follow
     fn take_branch(&mut self, branch: usize) {
-        if branch == 0 || branch == 1 {
+        let n = self.branches();
+        if branch < n {
             self.branch = branch;
             self.branch_chosen = true;
         } else {
-            panic!("Branch must be 0 or 1");
+            panic!("Branch {} out of range, rule {} has {} alternatives", branch, self.rule, n);
         }
     }
 
     fn push_r(&mut self) {
         self.branch_chosen = false;
         self.history.push((
-            self.rule,
+            self.rule.clone(),
             self.index,
-            self.branch
+            self.branch,
+            self.mark
         )); 
     }
 
     fn pop_r(&mut self) -> bool {
-        if let Some((r, i, b)) = self.history.pop() {
+        if let Some((r, i, b, m)) = self.history.pop() {
             self.rule = r;
             self.index = i;
             self.branch = b;
-            self.branch_chosen = true;
-            return true;
+            self.mark = m;
+            // Coming back to a repetition means choosing again whether to go
+            // round once more
+            self.branch_chosen = !matches!(self.current(), Rule::Repeat(..));
+            true
         } else {
-            return false;
+            false
         }
     }
 
     // Step the sequence, return true to continue, false means done.
-    fn step_seq(&mut self, seq: &Vec<usize>) -> bool {
+    fn step_seq(&mut self, seq: &[String]) -> bool {
         if self.index < seq.len() {
             // Investigate next rule in sequence
             let old_idx = self.index;
             self.index += 1;
             self.push_r();
-            self.rule = seq[old_idx];
+            self.rule = seq[old_idx].clone();
             self.index = 0;
         } else {
             // Done here
@@ -147,21 +329,17 @@ impl PatternMachine {
             }
         }
 
-        return true;
+        true
     }
 
-    fn next(&mut self) -> Option<char> {
+    fn next(&mut self) -> Option<Term> {
         loop {
-            let rule = self.rules.get(&self.rule).expect(format!("Bad rule index {}!", self.rule).as_str()).clone();
-            match rule {    
-                Rule::Or(b0, b1) => {
+            let rules = Rc::clone(&self.rules);
+            let rule = rules.get(&self.rule).unwrap_or_else(|| panic!("Bad rule name {}!", self.rule));
+            match rule {
+                Rule::Or(alts) => {
                     if self.branch_chosen {
-                        let b = if self.branch == 0 {
-                            b0
-                        } else {
-                            b1
-                        };
-                        if !self.step_seq(&b) {
+                        if !self.step_seq(&alts[self.branch]) {
                             self.end = true;
                             return None;
                         }
@@ -170,15 +348,45 @@ impl PatternMachine {
                     }
                 }
                 Rule::Seq(s) => {
-                    if !self.step_seq(&s) {
+                    if !self.step_seq(s) {
                         self.end = true;
                         return None;
                     }
                 }
-                Rule::Char(c) => {
-                    // Done here, pop one and return
-                    self.pop_r();
-                    return Some(c);
+                Rule::Repeat(item, min, max) => {
+                    // A round that read nothing would only repeat forever
+                    let stuck = self.index > 0 && self.read == self.mark;
+                    let again = if self.index < *min {
+                        true
+                    } else if *max == Some(self.index) || stuck {
+                        false
+                    } else if self.branch_chosen {
+                        self.branch == 0
+                    } else {
+                        return None;
+                    };
+                    if again {
+                        self.index += 1;
+                        self.mark = self.read;
+                        self.push_r();
+                        self.rule = item.clone();
+                        self.index = 0;
+                    } else if !self.pop_r() {
+                        self.end = true;
+                        return None;
+                    }
+                }
+                Rule::Lit(terms) => {
+                    if self.index < terms.len() {
+                        self.index += 1;
+                        self.read += 1;
+                        return Some(terms[self.index - 1].clone());
+                    }
+                    // Done here, back to whatever needed it
+                    if !self.pop_r() {
+                        self.end = true;
+                        return None;
+                    }
                 }
             }
         }
@@ -186,36 +394,33 @@ impl PatternMachine {
 }
 
 fn r_match(mut machine: PatternMachine, line: &str) -> bool {
-    if let Some(c) = machine.next() {
-        if line.len() == 0 {
+    if let Some(term) = machine.next() {
+        match line.chars().next() {
             // Expected to read but didn't
-            false
-        } else if c == line.chars().nth(0).unwrap() {
+            None => false,
             // Read fine, continue
-            r_match(machine, &line[1..])
-        } else {
+            Some(c) if term.matches(c) => r_match(machine, &line[c.len_utf8()..]),
             // Read wrong character, end
-            false
+            Some(_) => false
         }
+    } else if machine.is_end() {
+        // End, expect line to be empty now
+        line.is_empty()
+    } else if machine.is_branch() {
+        // Branch, split the machine for each alternative
+        (0..machine.branches()).any(|b| {
+            let mut m = machine.clone();
+            m.take_branch(b);
+            r_match(m, line)
+        })
     } else {
-        if machine.is_end() {
-            // End, expect line to be empty now
-            line.len() == 0
-        } else if machine.is_branch() {
-            // Branch, split the machine in two
-            let mut m0 = machine.clone();
-            m0.take_branch(0);
-            let mut m1 = machine;
-            m1.take_branch(1);
-            r_match(m0, line) || r_match(m1, line)
-        } else {
-            panic!("Machine ended without branch/end reason!");
-        }
+        panic!("Machine ended without branch/end reason!");
     }
 }
 
-fn rule_match(rules: &HashMap<usize, Rule>, line: &str) -> bool {
-    r_match(PatternMachine::new(rules.clone()), line)
+// Does the whole of `line` match the rule named `start`?
+fn rule_match(rules: &Rules, start: &str, line: &str) -> bool {
+    r_match(PatternMachine::new(Rc::new(rules.clone()), start), line)
 }
 
 fn main() {
@@ -225,19 +430,18 @@ fn main() {
 
     let mut rules = parse_rules(sections[0]);
     let lines: Vec<&str> = sections[1]
-        .split("\n")
+        .split('\n')
         .map(|s| s.trim())
-        .filter(|s| s.len() > 0)
+        .filter(|s| !s.is_empty())
         .collect();
     
-    let p1_count = lines.iter().filter(|l| rule_match(&rules, l)).count();
+    let p1_count = lines.iter().filter(|l| rule_match(&rules, "0", l)).count();
     println!("Part 1 count = {}", p1_count);
 
     // Modified rules for part 2
-    rules.insert(8, Rule::Or(vec![42], vec![42, 8]));
-    rules.insert(11, Rule::Or(vec![42, 31], vec![42, 11, 31]));
+    rules.extend(["8: 42+", "11: 42 31 | 42 11 31"].iter().flat_map(|l| parse_rule(l)));
 
-    let p2_count = lines.iter().filter(|l| rule_match(&rules, l)).count();
+    let p2_count = lines.iter().filter(|l| rule_match(&rules, "0", l)).count();
     println!("Part 2 count = {}", p2_count);
 }
 
@@ -245,6 +449,20 @@ fn main() {
 mod tests {
     use super::*;
 
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    // Every line in good matches rule, and none in bad does
+    fn check(rules: &Rules, rule: &str, good: &[&str], bad: &[&str]) {
+        for line in good {
+            assert!(rule_match(rules, rule, line), "{} should match {}", rule, line);
+        }
+        for line in bad {
+            assert!(!rule_match(rules, rule, line), "{} shouldn't match {}", rule, line);
+        }
+    }
+
     #[test]
     fn test_sample1() {
         let sample = "0: 4 1 5
@@ -254,24 +472,204 @@ mod tests {
 4: \"a\"
 5: \"b\"";
         let rules = parse_rules(sample);
-        assert_eq!(rules.get(&0), Some(&Rule::Seq(vec![4, 1, 5])));
-        assert_eq!(rules.get(&1), Some(&Rule::Or(vec![2, 3], vec![3, 2])));
-        assert_eq!(rules.get(&4), Some(&Rule::Char('a')));
+        assert_eq!(rules.get("0"), Some(&Rule::Seq(names(&["4", "1", "5"]))));
+        assert_eq!(rules.get("1"), Some(&Rule::Or(vec![names(&["2", "3"]), names(&["3", "2"])])));
+        assert_eq!(rules.get("4"), Some(&Rule::Lit(vec![Term::Char('a')])));
 
-        let mut machine = PatternMachine::new(rules.clone());
-        assert_eq!(machine.next(), Some('a'));
+        let mut machine = PatternMachine::new(Rc::new(rules.clone()), "0");
+        assert_eq!(machine.next(), Some(Term::Char('a')));
         assert_eq!(machine.next(), None);
         assert!(machine.is_branch());
         machine.take_branch(1);
         assert_eq!(machine.next(), None);
         machine.take_branch(0);
-        assert_eq!(machine.next(), Some('a'));
-        assert_eq!(machine.next(), Some('b'));
-
-        assert!(rule_match(&rules, "ababbb"));
-        assert!(!rule_match(&rules, "bababa"));
-        assert!(rule_match(&rules, "abbbab"));
-        assert!(!rule_match(&rules, "aaabbb"));
-        assert!(!rule_match(&rules, "aaaabbb"));
+        assert_eq!(machine.next(), Some(Term::Char('a')));
+        assert_eq!(machine.next(), Some(Term::Char('b')));
+
+        assert!(rule_match(&rules, "0", "ababbb"));
+        assert!(!rule_match(&rules, "0", "bababa"));
+        assert!(rule_match(&rules, "0", "abbbab"));
+        assert!(!rule_match(&rules, "0", "aaabbb"));
+        assert!(!rule_match(&rules, "0", "aaaabbb"));
+
+        // Any rule can be the start
+        assert!(rule_match(&rules, "1", "aaab"));
+        assert!(rule_match(&rules, "3", "ba"));
+        assert!(!rule_match(&rules, "3", "ababbb"));
+    }
+
+    #[test]
+    fn test_named_rules() {
+        let sample = "greeting: word \" \" name
+word: hi | yo
+hi: h i
+yo: y o | \"Y\" o
+name: 42 | bob
+bob: b \"o\" b
+42: \"x\"
+h: \"h\"
+i: \"i\"
+y: \"y\"
+o: \"o\"
+b: \"b\"";
+        let rules = parse_rules(sample);
+        assert_eq!(rules.get("greeting"), Some(&Rule::Seq(names(&["word", "greeting/1", "name"]))));
+        assert_eq!(rules.get("greeting/1"), Some(&Rule::Lit(vec![Term::Char(' ')])));
+        assert_eq!(rules.get("yo"), Some(&Rule::Or(vec![names(&["y", "o"]), names(&["yo/1", "o"])])));
+
+        assert!(rule_match(&rules, "greeting", "hi bob"));
+        assert!(rule_match(&rules, "greeting", "yo x"));
+        assert!(rule_match(&rules, "greeting", "Yo bob"));
+        assert!(!rule_match(&rules, "greeting", "hibob"));
+        assert!(!rule_match(&rules, "greeting", "hi"));
+        assert!(rule_match(&rules, "name", "bob"));
+    }
+
+    #[test]
+    fn test_many_alternatives() {
+        let sample = "0: 1 2 | 2 2 1 | 3 | 2 3 2
+1: \"a\"
+2: \"b\"
+3: 1 1 | 2 1 1 | 1 2 1 | 1 1 2
+pipe: \"|\" | 1 \"|\" | 2";
+        let rules = parse_rules(sample);
+        assert_eq!(rules.get("0"), Some(&Rule::Or(vec![
+            names(&["1", "2"]), names(&["2", "2", "1"]), names(&["3"]), names(&["2", "3", "2"])
+        ])));
+
+        let mut machine = PatternMachine::new(Rc::new(rules.clone()), "0");
+        assert_eq!(machine.next(), None);
+        assert_eq!(machine.branches(), 4);
+        machine.take_branch(3);
+        assert_eq!(machine.next(), Some(Term::Char('b')));
+
+        for line in ["ab", "bba", "aa", "baa", "aba", "aab", "baab", "bbaab"].iter() {
+            assert!(rule_match(&rules, "0", line), "{}", line);
+        }
+        for line in ["", "a", "ba", "abb", "bbb", "babb"].iter() {
+            assert!(!rule_match(&rules, "0", line), "{}", line);
+        }
+
+        // A quoted '|' doesn't split the alternatives
+        assert_eq!(rules.get("pipe"), Some(&Rule::Or(vec![names(&["pipe/1"]), names(&["1", "pipe/2"]), names(&["2"])])));
+        for line in ["|", "a|", "b"].iter() {
+            assert!(rule_match(&rules, "pipe", line), "{}", line);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Branch 2 out of range")]
+    fn test_bad_branch() {
+        let rules = parse_rules("0: 1 | 2\n1: \"a\"\n2: \"b\"");
+        let mut machine = PatternMachine::new(Rc::new(rules), "0");
+        assert_eq!(machine.next(), None);
+        machine.take_branch(2);
+    }
+
+    #[test]
+    fn test_literals_and_classes() {
+        let sample = r#"0: hello sep num
+hello: "héllo"
+sep: [^0-9a-z]
+num: digit | digit num
+digit: [0-9]
+quote: "say \"hi\" \\ bye"
+any: .
+brackets: [\]\-a-c]
+empty: ""
+inline: "hé" [0-9] . | [|] "\" |""#;
+        let rules = parse_rules(sample);
+        assert_eq!(rules.get("sep"), Some(&Rule::Lit(vec![Term::Class(vec![('0', '9'), ('a', 'z')], true)])));
+        assert_eq!(rules.get("brackets"), Some(&Rule::Lit(vec![Term::Class(vec![(']', ']'), ('-', '-'), ('a', 'c')], false)])));
+        assert_eq!(rules.get("empty"), Some(&Rule::Lit(vec![])));
+
+        assert!(rule_match(&rules, "0", "héllo 42"));
+        assert!(rule_match(&rules, "0", "héllo→7"));
+        assert!(!rule_match(&rules, "0", "hello 42"));
+        assert!(!rule_match(&rules, "0", "héllox42"));
+        assert!(!rule_match(&rules, "0", "héllo "));
+        assert!(rule_match(&rules, "quote", r#"say "hi" \ bye"#));
+        assert!(rule_match(&rules, "any", "日"));
+        assert!(!rule_match(&rules, "any", "日本"));
+        assert!(!rule_match(&rules, "any", ""));
+        for line in ["]", "-", "b"].iter() {
+            assert!(rule_match(&rules, "brackets", line), "{}", line);
+        }
+        assert!(!rule_match(&rules, "brackets", "d"));
+        assert!(rule_match(&rules, "empty", ""));
+        assert!(!rule_match(&rules, "empty", "a"));
+
+        // Written in place, each becomes a rule of its own
+        assert_eq!(rules.get("inline"), Some(&Rule::Or(vec![
+            names(&["inline/1", "inline/2", "inline/3"]), names(&["inline/4", "inline/5"])
+        ])));
+        assert_eq!(rules.get("inline/3"), Some(&Rule::Lit(vec![Term::Any])));
+        for line in ["hé5x", "hé0日", r#"|" |"#].iter() {
+            assert!(rule_match(&rules, "inline", line), "{}", line);
+        }
+        for line in ["hé5", "he5x", "hé55x5", "|"].iter() {
+            assert!(!rule_match(&rules, "inline", line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_repetition() {
+        let sample = r#"x: (a b)? c*
+y: a+ | (b | c c)+ a?
+z: (a? b?)* c
+nest: (a (b c)*)+
+a: "a"
+b: "b"
+c: "c""#;
+        let rules = parse_rules(sample);
+        assert_eq!(rules.get("x"), Some(&Rule::Seq(names(&["x/2", "x/3"]))));
+        assert_eq!(rules.get("x/1"), Some(&Rule::Seq(names(&["a", "b"]))));
+        assert_eq!(rules.get("x/2"), Some(&Rule::Repeat("x/1".to_string(), 0, Some(1))));
+        assert_eq!(rules.get("x/3"), Some(&Rule::Repeat("c".to_string(), 0, None)));
+        assert_eq!(rules.get("y"), Some(&Rule::Or(vec![names(&["y/1"]), names(&["y/3", "y/4"])])));
+
+        check(&rules, "x", &["", "ab", "abccc", "ccc"], &["a", "abab", "cab", "abcb"]);
+        check(&rules, "y", &["a", "aaa", "b", "ccbcca", "bbb"], &["", "c", "ba a", "ab", "bcb"]);
+        // Rounds that read nothing don't loop forever
+        check(&rules, "z", &["c", "abbac", "bbbc"], &["", "ab", "cc"]);
+        check(&rules, "nest", &["a", "abcbc", "aabca"], &["", "bc", "abcb"]);
+    }
+
+    #[test]
+    fn test_part2_with_repetition() {
+        // 8: 42 | 42 8 is the same as 8: 42+
+        let sample = "0: 8 11
+8: 42+
+11: 42 31 | 42 11 31
+42: \"a\"
+31: \"b\"";
+        let rules = parse_rules(sample);
+        assert!(rule_match(&rules, "0", "aab"));
+        assert!(rule_match(&rules, "0", "aaaabb"));
+        assert!(!rule_match(&rules, "0", "aabb"));
+        assert!(!rule_match(&rules, "0", "ab"));
+    }
+
+    #[test]
+    fn test_inline_literals() {
+        let sample = r#"greeting: word " " name
+word: "hi" | "yo"
+name: [A-Z] [a-z]*
+num: [0-9]+ ("." [0-9]+)?
+abs: "ab"+ . [^\]]?"#;
+        let rules = parse_rules(sample);
+        assert_eq!(rules.get("greeting"), Some(&Rule::Seq(names(&["word", "greeting/1", "name"]))));
+        assert_eq!(rules.get("greeting/1"), Some(&Rule::Lit(vec![Term::Char(' ')])));
+        assert_eq!(rules.get("word"), Some(&Rule::Or(vec![names(&["word/1"]), names(&["word/2"])])));
+
+        check(&rules, "greeting", &["hi Bob", "yo X"], &["hi bob", "hiBob", "hey Bob", "hi  Bob"]);
+        check(&rules, "num", &["7", "42", "3.14"], &["", "4.", ".5", "1a"]);
+        check(&rules, "abs", &["abx", "ababab]", "ab[["], &["ab", "aab", "abab]]"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unclosed '(' in rule x")]
+    fn test_unclosed_group() {
+        parse_rules("x: (a b c");
     }
 }